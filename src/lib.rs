@@ -0,0 +1,6934 @@
+use float_cmp::ApproxEq;
+use nom::bytes::complete::{is_not, tag, take_until};
+use nom::{multi::*, sequence::*};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use regex::Regex;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::io::{BufRead, BufReader, Cursor, Read};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{env, fmt, fs::File, io, str::FromStr};
+
+#[derive(Debug)]
+pub enum StoryError {
+    LoopLimit {
+        line: usize,
+        limit: usize,
+    },
+    UnmatchedBlock {
+        line: usize,
+        text: String,
+    },
+    Function {
+        name: String,
+        message: String,
+        line: usize,
+    },
+    InvalidEnumValue {
+        name: String,
+        value: String,
+        allowed: Vec<String>,
+        line: usize,
+    },
+    CyclicDefault {
+        names: Vec<String>,
+        line: usize,
+    },
+    Malformed {
+        line: usize,
+        text: String,
+        reason: String,
+    },
+    LineTooLong {
+        line: usize,
+        length: usize,
+        limit: usize,
+    },
+    MissingVariable {
+        name: String,
+        line: usize,
+    },
+    UnknownLabel {
+        label: String,
+    },
+    NotANumber {
+        operand: String,
+        line: usize,
+    },
+    Include {
+        line: usize,
+        path: String,
+        reason: String,
+    },
+    IncludeCycle {
+        line: usize,
+        files: Vec<String>,
+    },
+    Archive {
+        path: String,
+        reason: String,
+    },
+    Open {
+        path: String,
+        reason: String,
+    },
+    Save {
+        path: String,
+        reason: String,
+    },
+    ReturnWithoutGosub {
+        line: usize,
+    },
+    Timeout {
+        line: usize,
+        limit: Duration,
+    },
+    DuplicateHotkey {
+        key: char,
+        first_line: usize,
+        line: usize,
+    },
+    TooManyOptions {
+        line: usize,
+        first_line: usize,
+        limit: usize,
+    },
+    TypeMismatch {
+        left: String,
+        right: String,
+        kinds: (&'static str, &'static str),
+        op: String,
+        line: usize,
+    },
+    DuplicateLabel {
+        name: String,
+        first_line: usize,
+        second_line: usize,
+    },
+    StepLimitExceeded {
+        line: usize,
+        limit: usize,
+    },
+    UnexpectedEof {
+        line: usize,
+    },
+}
+
+impl fmt::Display for StoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoryError::LoopLimit { line, limit } => write!(
+                f,
+                "Loop on line {} ran more than {} times. Check its condition or raise the limit.",
+                line, limit
+            ),
+            StoryError::UnmatchedBlock { line, text } => {
+                write!(f, "Block {} on line {} has no matching end.", text, line)
+            }
+            StoryError::Function {
+                name,
+                message,
+                line,
+            } => write!(f, "{}() {}. Error on line {}.", name, message, line),
+            StoryError::InvalidEnumValue {
+                name,
+                value,
+                allowed,
+                line,
+            } => write!(
+                f,
+                "{} is not a valid value for @{}, it must be one of {}. Error on line {}.",
+                value,
+                name,
+                allowed.join(", "),
+                line
+            ),
+            StoryError::Malformed { line, text, reason } => {
+                write!(f, "Line {} \"{}\" is malformed, {}.", line, text, reason)
+            }
+            StoryError::LineTooLong {
+                line,
+                length,
+                limit,
+            } => write!(
+                f,
+                "Line {} is {} bytes long but lines may only be {} bytes.",
+                line, length, limit
+            ),
+            StoryError::MissingVariable { name, line } => write!(
+                f,
+                "Variable @{} is missing on line {}. It must be created before the block using it.",
+                name, line
+            ),
+            StoryError::UnknownLabel { label } => {
+                write!(f, "There is no label :{} in the story.", label)
+            }
+            StoryError::NotANumber { operand, line } => write!(
+                f,
+                "{} is not a number but is compared as one. Error on line {}.",
+                operand, line
+            ),
+            StoryError::Include { line, path, reason } => write!(
+                f,
+                "Could not include {} on line {}, {}.",
+                path, line, reason
+            ),
+            StoryError::IncludeCycle { line, files } => write!(
+                f,
+                "The include on line {} goes round in a circle, {}.",
+                line,
+                files.join(" -> ")
+            ),
+            StoryError::Archive { path, reason } => {
+                write!(f, "Could not load the story from {}, {}.", path, reason)
+            }
+            StoryError::Open { path, reason } => {
+                write!(f, "Could not open {}, {}.", path, reason)
+            }
+            StoryError::Save { path, reason } => {
+                write!(f, "Could not continue from {}, {}.", path, reason)
+            }
+            StoryError::ReturnWithoutGosub { line } => write!(
+                f,
+                "Line {} returns with < but no >label was called to return from.",
+                line
+            ),
+            StoryError::Timeout { line, limit } => write!(
+                f,
+                "Story did not finish within {:.1} seconds, stopped on line {}.",
+                limit.as_secs_f64(),
+                line
+            ),
+            StoryError::DuplicateHotkey {
+                key,
+                first_line,
+                line,
+            } => write!(
+                f,
+                "Hotkey [{}] on line {} is already used on line {} of the same question.",
+                key, line, first_line
+            ),
+            StoryError::TooManyOptions {
+                line,
+                first_line,
+                limit,
+            } => write!(
+                f,
+                "The question starting on line {} has more than {} options, line {} is one too many.",
+                first_line, limit, line
+            ),
+            StoryError::DuplicateLabel {
+                name,
+                first_line,
+                second_line,
+            } => write!(
+                f,
+                "Label :{} on line {} is already used on line {}.",
+                name, second_line, first_line
+            ),
+            StoryError::StepLimitExceeded { line, limit } => write!(
+                f,
+                "The story ran more than {} lines and was stopped on line {}. Check for a goto that loops forever.",
+                limit, line
+            ),
+            StoryError::UnexpectedEof { line } => write!(
+                f,
+                "Input ended while the story was waiting for an answer on line {}.",
+                line
+            ),
+            //text can only be checked for being the same, so < on two texts says that instead.
+            StoryError::TypeMismatch {
+                left,
+                right,
+                kinds,
+                op,
+                line,
+            } if kinds.0 == kinds.1 => write!(
+                f,
+                "{} and {} are both {}, so {} can not be used on them. Error on line {}.",
+                left, right, kinds.0, op, line
+            ),
+            StoryError::TypeMismatch {
+                left,
+                right,
+                kinds,
+                op,
+                line,
+            } => write!(
+                f,
+                "{} is {} but {} is {}, so {} can not be used on them. Error on line {}.",
+                left, kinds.0, right, kinds.1, op, line
+            ),
+            StoryError::CyclicDefault { names, line } => write!(
+                f,
+                "The starting values of @{} depend on each other. Error on line {}.",
+                names.join(" -> @"),
+                line
+            ),
+        }
+    }
+}
+
+impl StoryError {
+    //the story line the error is on, counted from 1, for errors that have one.
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            StoryError::LoopLimit { line, .. }
+            | StoryError::UnmatchedBlock { line, .. }
+            | StoryError::Function { line, .. }
+            | StoryError::InvalidEnumValue { line, .. }
+            | StoryError::CyclicDefault { line, .. }
+            | StoryError::Malformed { line, .. }
+            | StoryError::LineTooLong { line, .. }
+            | StoryError::MissingVariable { line, .. }
+            | StoryError::NotANumber { line, .. }
+            | StoryError::Include { line, .. }
+            | StoryError::IncludeCycle { line, .. }
+            | StoryError::ReturnWithoutGosub { line, .. }
+            | StoryError::Timeout { line, .. }
+            | StoryError::DuplicateHotkey { line, .. }
+            | StoryError::TooManyOptions { line, .. }
+            | StoryError::TypeMismatch { line, .. }
+            | StoryError::StepLimitExceeded { line, .. }
+            | StoryError::UnexpectedEof { line } => Some(*line),
+            StoryError::DuplicateLabel { second_line, .. } => Some(*second_line),
+            StoryError::UnknownLabel { .. }
+            | StoryError::Archive { .. }
+            | StoryError::Open { .. }
+            | StoryError::Save { .. } => None,
+        }
+    }
+
+    //recoverable problems leave the story able to run, with something not working as intended.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(
+            self,
+            StoryError::DuplicateHotkey { .. } | StoryError::InvalidEnumValue { .. }
+        )
+    }
+}
+
+impl std::error::Error for StoryError {}
+
+//what the story shows, kept apart from how it is drawn so a GUI can render it its own way.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputEvent {
+    Narrative(String),
+    MenuOption {
+        n: usize,
+        hotkey: Option<char>,
+        text: String,
+        //the option as the story wants it shown, like 1. text
+        display: String,
+    },
+    Prompt(String),
+    Clear,
+    Hud(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorMode {
+    //colors unless NO_COLOR is set or the output is not a terminal.
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepResult {
+    Continue,
+    Finished,
+    //stopped before running this line, which has a breakpoint on it.
+    Breakpoint(usize),
+}
+
+pub trait StoryIo {
+    fn emit(&mut self, event: OutputEvent);
+    //None once there is nothing left to read, like after Ctrl-D or the end of a piped file.
+    fn read_line(&mut self) -> Option<String>;
+
+    //warnings and *stderr lines, kept out of the story's own output so transcripts stay clean.
+    fn write_err(&mut self, text: &str) {
+        eprintln!("{}", text);
+    }
+
+    //the terminal front end, so its streams can be swapped one at a time.
+    fn as_std_io(&mut self) -> Option<&mut StdIo> {
+        None
+    }
+}
+
+//the default terminal front end, using stdout and stdin unless others are given.
+pub struct StdIo {
+    pub output: Box<dyn Write>,
+    pub input: Box<dyn BufRead>,
+    pub error: Box<dyn Write>,
+}
+
+impl StdIo {
+    pub fn new() -> StdIo {
+        StdIo {
+            output: Box::new(io::stdout()),
+            input: Box::new(BufReader::new(io::stdin())),
+            error: Box::new(io::stderr()),
+        }
+    }
+}
+
+impl Default for StdIo {
+    fn default() -> Self {
+        StdIo::new()
+    }
+}
+
+impl StoryIo for StdIo {
+    fn emit(&mut self, event: OutputEvent) {
+        let written = match event {
+            OutputEvent::Narrative(text) | OutputEvent::Prompt(text) | OutputEvent::Hud(text) => {
+                writeln!(self.output, "{}", text)
+            }
+            OutputEvent::MenuOption { display, .. } => writeln!(self.output, "{}", display),
+            OutputEvent::Clear => self.output.write_all(b"\x1b[2J\x1b[1;1H"),
+        };
+
+        //output that was closed, like when piped into head, is no reason to stop the story.
+        written.ok();
+    }
+
+    //input that can not be read, like a line that is not UTF-8, ends it the same as Ctrl-D.
+    fn read_line(&mut self) -> Option<String> {
+        let mut rv = String::new();
+        self.output.flush().ok();
+
+        match self.input.read_line(&mut rv) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(rv.replace("\r\n", "").replace("\n", "")),
+        }
+    }
+
+    fn write_err(&mut self, text: &str) {
+        writeln!(self.error, "{}", text).ok();
+    }
+
+    fn as_std_io(&mut self) -> Option<&mut StdIo> {
+        Some(self)
+    }
+}
+
+//where the story gets the time from, swapped out so timed stories can be tested.
+pub trait Clock {
+    //time passed since some fixed point, only differences between calls matter.
+    fn now(&self) -> Duration;
+}
+
+pub struct SystemClock {
+    pub start: Instant,
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+//the variables as they are, for an ExpressionEngine that wants them, though the ones used
+//are already filled into the expression it is given.
+pub type VarView = HashMap<String, Value>;
+
+//why an expression could not be worked out, it is then kept as text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExprError(pub String);
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+//works out assignments and the sides of conditions once variables and functions are filled in,
+//swapped out to use a richer evaluator than tinyexpr.
+pub trait ExpressionEngine {
+    fn eval(&self, expr: &str, vars: &VarView) -> Result<Value, ExprError>;
+}
+
+//the default engine, numbers and math through tinyexpr.
+pub struct TinyExpr;
+
+impl ExpressionEngine for TinyExpr {
+    fn eval(&self, expr: &str, _vars: &VarView) -> Result<Value, ExprError> {
+        tinyexpr::interp(expr)
+            .map(Value::Num)
+            .map_err(|_| ExprError(format!("{} is not a number or a sum", expr.trim())))
+    }
+}
+
+//the state needed to go back to just before a question or input.
+#[derive(Debug, Clone)]
+struct Snapshot {
+    index: usize,
+    variables: HashMap<String, Value>,
+    lists: HashMap<String, Vec<String>>,
+    visited: HashSet<String>,
+    loop_counts: HashMap<usize, usize>,
+    answers: Vec<Answer>,
+    call_stack: Vec<usize>,
+}
+
+//an option picked in a question block.
+#[derive(Debug, Clone, Serialize)]
+pub struct Answer {
+    //the line of the first option in the block.
+    pub line: usize,
+    pub option: usize,
+    pub text: String,
+    //whether it was the option marked ?* as right, if the question had one.
+    pub correct: Option<bool>,
+}
+
+//written out when the story ends so quizzes can be marked by whatever ran them.
+#[derive(Debug, Clone, Serialize)]
+pub struct StoryResult {
+    //the story's @score variable, or how many ?* options were picked if it has none.
+    pub score: Option<f64>,
+    pub answers: Vec<Answer>,
+    //the last label the story passed through.
+    pub ending: Option<String>,
+}
+
+//what a story expects a variable to hold, so saves can be checked against it.
+#[derive(Debug, Clone, Serialize)]
+pub struct VariableSchema {
+    pub name: String,
+    //enum or list when the story says so, otherwise left out.
+    pub kind: Option<String>,
+    pub allowed: Option<Vec<String>>,
+    pub default: String,
+    pub description: Option<String>,
+}
+
+//what a variable holds, worked out once when it is assigned instead of every time it is used.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Num(f64),
+    Str(String),
+    Bool(bool),
+}
+
+impl Value {
+    //numbers become Num, true and false become Bool and anything else is kept as text.
+    pub fn infer(text: &str) -> Value {
+        match text.trim() {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            trimmed => match f64::from_str(trimmed) {
+                Ok(n) if !trimmed.is_empty() => Value::Num(n),
+                _ => Value::Str(text.to_string()),
+            },
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Num(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    //what the value is called in errors.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Value::Num(_) => "number",
+            Value::Str(_) => "text",
+            Value::Bool(_) => "true or false",
+        }
+    }
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Value::Str(String::new())
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Num(n) => write!(f, "{}", n),
+            Value::Str(s) => write!(f, "{}", s),
+            Value::Bool(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+//each condition of a ! line with the action taken when it holds.
+type IfBranches = Vec<(String, String)>;
+
+//the lowest and highest number an ^i input takes.
+type Bounds = Option<(f64, f64)>;
+
+//plays a story, build one with new, load it with processfile then play it with run.
+pub struct Renderer {
+    lines: Vec<String>,
+    variables: HashMap<String, Value>,
+    lists: HashMap<String, Vec<String>>,
+    //the allowed values of variables declared as @name:enum(a,b,c)
+    enums: HashMap<String, Vec<String>>,
+    labels: HashMap<String, usize>,
+    index: usize,
+    //when set, lines run silently with inputs auto answered until this label is reached.
+    pub advance_to: Option<String>,
+    //maps a ~while line to its ~endwhile line and back again.
+    blocks: HashMap<usize, usize>,
+    //used when a ~while does not give its own limit.
+    pub loop_limit: usize,
+    loop_counts: HashMap<usize, usize>,
+    visited: HashSet<String>,
+    io: Box<dyn StoryIo>,
+    //markers that start a trailing comment on command lines, like // or ;
+    pub comment_markers: Vec<String>,
+    history: Vec<Snapshot>,
+    //how many snapshots are kept for undo before the oldest is dropped.
+    pub history_limit: usize,
+    //where the most recent question block started, used by #menu
+    last_menu: Option<usize>,
+    //waits for Enter once the story ends so the last text is not lost when the window closes.
+    pub pause_on_finish: bool,
+    finished: bool,
+    //narrative is wrapped to this many columns, Some(0) turns wrapping off.
+    pub wrap_width: Option<usize>,
+    //asked for the width when wrap_width is not set, swapped out when there is no terminal.
+    pub width_query: fn() -> Option<usize>,
+    //the value each variable starts with, worked out when the story is loaded.
+    defaults: HashMap<String, Value>,
+    //when off [color red]text[/color] is printed as written.
+    pub color_markup: bool,
+    pub color: ColorMode,
+    //whether NO_COLOR was set when the renderer was made.
+    pub no_color: bool,
+    //asked when color is Auto, given no_color, swapped out to test without a terminal.
+    pub color_support: fn(bool) -> bool,
+    pub clock: Box<dyn Clock>,
+    pub expressions: Box<dyn ExpressionEngine>,
+    //when the first line ran, by the clock.
+    started: Option<Duration>,
+    //longer lines are refused when the story loads, or cut down if truncate_long_lines is set.
+    pub max_line_length: usize,
+    pub truncate_long_lines: bool,
+    //lets #Intro find :intro, must be set before the story is loaded.
+    pub case_insensitive_labels: bool,
+    //patterns used by ^r inputs, compiled once when the story is loaded.
+    regexes: HashMap<String, Regex>,
+    //taken from a comment on an assignment, like @gold = 0 // the player's money
+    descriptions: HashMap<String, String>,
+    //conditions treat a variable that was never created as 0, so !@met_king==1 is just false.
+    pub lenient: bool,
+    //used by rand and randf, seed it with --seed to get the same rolls every run. It sits in a
+    //RefCell so expressions can be worked out through &self.
+    rng: RefCell<StdRng>,
+    answers: Vec<Answer>,
+    last_label: Option<String>,
+    //*include paths are looked for here, the story file's own folder.
+    pub base_dir: PathBuf,
+    //the story file itself, so a story that includes itself is caught before it is read twice.
+    pub story_path: Option<PathBuf>,
+    //when the *timer runs out by the clock.
+    timer_end: Option<Duration>,
+    //answers to ^ inputs have spaces taken off both ends.
+    pub trim_input: bool,
+    //a side of a comparison that is not a number is an error instead of being compared as text.
+    pub strict_numbers: bool,
+    //numbers this close count as equal in == and !=, so 0.1 + 0.2 == 0.3 holds with 1e-9. It is 0
+    //by default, where only the exact same number is equal.
+    pub epsilon: f64,
+    //the files of the archive the story was loaded from, by their path inside it.
+    archive: Option<HashMap<PathBuf, Vec<u8>>>,
+    //refuses #@variable gotos when loading so every path through the story can be checked.
+    pub strict_flow: bool,
+    //how numbered options are shown, the first {} is the number and the second the text.
+    pub option_format: String,
+    //set while run_streaming plays the story a label at a time.
+    streaming: bool,
+    //a goto past the end of the lines held while streaming, found as more of the story is read.
+    pending_label: Option<String>,
+    //checkpoints are saved here, nothing is saved while it is None.
+    pub save_path: Option<PathBuf>,
+    //checkpoints reached sooner than this after the last save are passed without saving.
+    pub save_interval: Duration,
+    //when the last checkpoint was saved, by the clock.
+    last_save: Option<Duration>,
+    //where each >label call returns to with <, the latest call last.
+    call_stack: Vec<usize>,
+    //lines read in by *include file as name, whose labels are known as name.label
+    namespaces: Vec<(Range<usize>, String)>,
+    //for each line of the story, the included file it came from, None for the story itself,
+    //and its line in that file counted from 1.
+    origins: Vec<(Option<PathBuf>, usize)>,
+    //the most options one question may have, to stop a runaway file building a huge menu.
+    pub max_options: usize,
+    //lines, counted from 1, that step stops in front of.
+    breakpoints: HashSet<usize>,
+    //the breakpoint just stopped at, so the next step runs its line instead of stopping again.
+    paused_at: Option<usize>,
+    //the most lines the story may run before it is stopped, None for no limit.
+    pub max_steps: Option<usize>,
+    steps: usize,
+    //reaching this label sends the player back to the last :*checkpoint instead of playing it.
+    pub death_label: Option<String>,
+    //shown, waiting for Enter, before going back.
+    pub death_prompt: Option<String>,
+    last_checkpoint: Option<Snapshot>,
+}
+
+impl Default for Renderer {
+    fn default() -> Self {
+        Renderer::new()
+    }
+}
+
+impl Renderer {
+    pub fn new() -> Renderer {
+        Renderer {
+            lines: Vec::new(),
+            variables: HashMap::new(),
+            lists: HashMap::new(),
+            enums: HashMap::new(),
+            labels: HashMap::new(),
+            index: 0,
+            advance_to: None,
+            blocks: HashMap::new(),
+            loop_limit: 10000,
+            loop_counts: HashMap::new(),
+            visited: HashSet::new(),
+            io: Box::new(StdIo::new()),
+            comment_markers: vec![String::from("//")],
+            history: Vec::new(),
+            history_limit: 32,
+            last_menu: None,
+            pause_on_finish: false,
+            finished: false,
+            wrap_width: None,
+            width_query: terminal_width,
+            defaults: HashMap::new(),
+            color_markup: true,
+            color: ColorMode::Auto,
+            no_color: matches!(env::var_os("NO_COLOR"), Some(v) if !v.is_empty()),
+            color_support: terminal_color,
+            expressions: Box::new(TinyExpr),
+            clock: Box::new(SystemClock {
+                start: Instant::now(),
+            }),
+            started: None,
+            max_line_length: 65536,
+            truncate_long_lines: false,
+            case_insensitive_labels: false,
+            regexes: HashMap::new(),
+            descriptions: HashMap::new(),
+            lenient: false,
+            rng: RefCell::new(StdRng::from_entropy()),
+            answers: Vec::new(),
+            last_label: None,
+            base_dir: PathBuf::new(),
+            story_path: None,
+            timer_end: None,
+            trim_input: false,
+            strict_numbers: false,
+            epsilon: 0.0,
+            archive: None,
+            strict_flow: false,
+            option_format: String::from("{}. {}"),
+            streaming: false,
+            pending_label: None,
+            save_path: None,
+            save_interval: Duration::from_secs(5),
+            last_save: None,
+            call_stack: Vec::new(),
+            namespaces: Vec::new(),
+            origins: Vec::new(),
+            max_options: 500,
+            breakpoints: HashSet::new(),
+            paused_at: None,
+            max_steps: None,
+            steps: 0,
+            death_label: None,
+            death_prompt: None,
+            last_checkpoint: None,
+        }
+    }
+
+    //story text is written to output instead of stdout. These chain, so Renderer::new()
+    //.with_input(answers).with_output(transcript) plays a scripted run and keeps what it showed.
+    pub fn with_output(mut self, output: Box<dyn Write>) -> Renderer {
+        self.std_io().output = output;
+        self
+    }
+
+    //warnings and *stderr lines go to error instead of stderr.
+    pub fn with_error(mut self, error: Box<dyn Write>) -> Renderer {
+        self.std_io().error = error;
+        self
+    }
+
+    //answers are read from input instead of stdin, like a Cursor of scripted answers.
+    pub fn with_input(mut self, input: Box<dyn BufRead>) -> Renderer {
+        self.std_io().input = input;
+        self
+    }
+
+    //a front end of its own, like a GUI, in place of the terminal.
+    pub fn with_io(mut self, io: Box<dyn StoryIo>) -> Renderer {
+        self.io = io;
+        self
+    }
+
+    //rand, randf, chance and *branch roll the same numbers on every run.
+    pub fn with_seed(mut self, seed: u64) -> Renderer {
+        self.rng = RefCell::new(StdRng::seed_from_u64(seed));
+        self
+    }
+
+    //with_input, with_output and with_error swap streams of the terminal front end, which takes
+    //the place of one given to with_io.
+    fn std_io(&mut self) -> &mut StdIo {
+        if self.io.as_std_io().is_none() {
+            self.io = Box::new(StdIo::new());
+        }
+
+        self.io.as_std_io().unwrap()
+    }
+
+    //every problem found is returned, so a story can be fixed in one pass instead of one error at a time.
+    pub fn processfile(&mut self, file: impl Read) -> Result<(), Vec<StoryError>> {
+        let mut errors: Vec<StoryError> = Vec::new();
+        let base_dir = self.base_dir.clone();
+        let mut story = Vec::new();
+        let mut including: Vec<PathBuf> =
+            self.story_path.iter().map(|p| self.file_key(p)).collect();
+        self.read_story(
+            BufReader::new(file),
+            &base_dir,
+            None,
+            &mut including,
+            &mut story,
+            &mut errors,
+        );
+        let mut open_blocks: Vec<usize> = Vec::new();
+        //the first assignment of each variable, which gives its starting value.
+        let mut declarations: Vec<(String, String, usize)> = Vec::new();
+        //hotkeys of the question block being read and the line each came from.
+        let mut hotkeys: HashMap<char, usize> = HashMap::new();
+        let mut options: Vec<usize> = Vec::new();
+        //*branch lines, checked once every label is known.
+        let mut branches: Vec<usize> = Vec::new();
+        //variables only ever given a value by @name ?= value, which start out empty.
+        let mut optional: Vec<String> = Vec::new();
+
+        for (index, mut text) in story.into_iter().enumerate() {
+            if text.len() > self.max_line_length {
+                if !self.truncate_long_lines {
+                    errors.push(StoryError::LineTooLong {
+                        line: index + 1,
+                        length: text.len(),
+                        limit: self.max_line_length,
+                    });
+
+                    //kept as an empty line so the lines after it keep their numbers.
+                    self.lines.push(String::new());
+                    continue;
+                }
+
+                self.io.write_err(&format!(
+                    "Warning: line {} is {} bytes long and was cut to {} bytes.",
+                    index + 1,
+                    text.len(),
+                    self.max_line_length
+                ));
+
+                let mut end = self.max_line_length;
+
+                while !text.is_char_boundary(end) {
+                    end -= 1;
+                }
+
+                text.truncate(end);
+            }
+
+            //lines inside *preformatted are kept exactly as written, only *endpre is read.
+            if matches!(open_blocks.last(), Some(start) if split_directive(&self.lines[*start]).0 == "preformatted")
+                && !text.trim().eq_ignore_ascii_case("*endpre")
+            {
+                self.lines.push(text);
+                continue;
+            }
+
+            let (text, comment) = self.strip_comment(unindent_command(text));
+            self.lines.push(text.clone());
+
+            if text.starts_with('?') {
+                options.push(index);
+
+                //reported once, on the option that goes over.
+                if options.len() == self.max_options + 1 {
+                    errors.push(StoryError::TooManyOptions {
+                        line: index + 1,
+                        first_line: options[0] + 1,
+                        limit: self.max_options,
+                    });
+                }
+            } else {
+                errors.extend(hotkey_number_conflicts(&hotkeys, &options));
+                hotkeys.clear();
+                options.clear();
+            }
+
+            if text.is_empty() {
+                continue;
+            }
+
+            let malformed = |reason: &str| StoryError::Malformed {
+                line: index + 1,
+                text: text.clone(),
+                reason: reason.to_string(),
+            };
+
+            if self.strict_flow && computed_goto(&text) {
+                errors.push(malformed(
+                    "gotos to a @variable are not allowed with strict flow",
+                ));
+            }
+
+            if let Some(broken) = self.broken_expression_in(&text) {
+                errors.push(malformed(&format!("{} can never be worked out", broken)));
+            }
+
+            match text.get(0..1).unwrap_or_default() {
+                "?" => {
+                    if self.tokenize(text.clone(), ":").is_err() {
+                        errors.push(malformed("a question option needs one : before its #label"));
+                    }
+
+                    let (correct, option) = parse_correct(&text[1..]);
+
+                    if let (Some(key), _) = parse_hotkey(option) {
+                        if let Some(first) = hotkeys.insert(key, index) {
+                            errors.push(StoryError::DuplicateHotkey {
+                                key,
+                                first_line: first + 1,
+                                line: index + 1,
+                            });
+                        }
+                    }
+
+                    if correct {
+                        for name in &["_correct", "_incorrect"] {
+                            self.variables
+                                .entry(name.to_string())
+                                .or_insert(Value::Num(0.0));
+                            self.defaults.insert(name.to_string(), Value::Num(0.0));
+                        }
+                    }
+                }
+                "!" => {
+                    if self.iftokenize(text.clone(), ":").is_err() {
+                        errors.push(malformed("an if needs a condition, a : and an action"));
+                    }
+                }
+                ":" => {
+                    let (_, name) = parse_checkpoint(&text[1..]);
+                    let name = self.qualified(name, index);
+                    let key = self.label_key(&name);
+
+                    //gotos keep going to the first one, the second would otherwise take over silently.
+                    match self.labels.get(&key) {
+                        Some(first) => errors.push(StoryError::DuplicateLabel {
+                            name,
+                            first_line: first + 1,
+                            second_line: index + 1,
+                        }),
+                        None => {
+                            self.labels.insert(key, index);
+                        }
+                    }
+                }
+                "@" => {
+                    if let Some((l, r)) = split_assignment(&text) {
+                        //@gold += 5 changes a variable made elsewhere, it does not make one.
+                        if let Some((name, op)) = split_compound(&l) {
+                            if op == '?' {
+                                optional.push(name.to_string());
+                            }
+
+                            continue;
+                        }
+
+                        let (name, allowed) = parse_declaration(&l[1..]);
+
+                        if name.is_empty() {
+                            errors.push(malformed("an assignment needs a variable name"));
+                            continue;
+                        }
+
+                        match allowed {
+                            Some(allowed) => {
+                                self.enums.insert(name.clone(), allowed);
+                            }
+                            None if l.contains(':') => {
+                                errors.push(malformed("the only variable type is enum(a,b,c)"));
+                            }
+                            None => {}
+                        }
+
+                        if list_call(&r, "split").is_some() {
+                            self.lists.entry(name).or_default();
+                            continue;
+                        }
+
+                        if !self.variables.contains_key(&name) {
+                            declarations.push((name.clone(), r, index));
+                        }
+
+                        if let Some(comment) = comment.filter(|c| !c.is_empty()) {
+                            self.descriptions.entry(name.clone()).or_insert(comment);
+                        }
+
+                        self.variables.entry(name).or_insert(Value::Num(0.0));
+                    } else if split_dialogue(&text).is_some() {
+                        self.variables.entry(String::from("_speaker")).or_default();
+                        self.defaults.entry(String::from("_speaker")).or_default();
+                    }
+                }
+                "^" if text.starts_with("^r:") => match split_pattern_input(&text[3..]) {
+                    Some((name, pattern, _)) => match Regex::new(pattern) {
+                        Ok(re) => {
+                            self.regexes.insert(pattern.to_string(), re);
+                            self.variables.entry(name.to_string()).or_default();
+                        }
+                        Err(e) => {
+                            errors.push(malformed(&format!("the pattern is not valid, {}", e)))
+                        }
+                    },
+                    None => errors.push(malformed(
+                        "input should look like ^r:variable /pattern/ prompt",
+                    )),
+                },
+                "^" => match text.strip_prefix("^l:") {
+                    Some(rest) => {
+                        let (name, _) = split_list_input(rest);
+                        self.lists.entry(name.to_string()).or_default();
+                    }
+                    None => {
+                        let checked = self.split_input(&text).and_then(|(kind, prompt, _)| {
+                            split_input_prompt(&prompt, kind).map(|_| ())
+                        });
+
+                        if let Err(reason) = checked {
+                            errors.push(malformed(&reason));
+                        }
+                    }
+                },
+                "*" => match &split_directive(&text).0[..] {
+                    "table" | "preformatted" => open_blocks.push(index),
+                    "endtable" => match open_blocks.pop() {
+                        Some(start) if split_directive(&self.lines[start]).0 == "table" => {
+                            self.blocks.insert(start, index);
+                        }
+                        _ => errors.push(StoryError::UnmatchedBlock {
+                            line: index + 1,
+                            text,
+                        }),
+                    },
+                    "endpre" => match open_blocks.pop() {
+                        Some(start) if split_directive(&self.lines[start]).0 == "preformatted" => {
+                            self.blocks.insert(start, index);
+                        }
+                        _ => errors.push(StoryError::UnmatchedBlock {
+                            line: index + 1,
+                            text,
+                        }),
+                    },
+                    "timer" => {
+                        if let Err(reason) = split_timer(split_directive(&text).1) {
+                            errors.push(malformed(&reason));
+                        }
+
+                        self.variables
+                            .entry(String::from("_timer"))
+                            .or_insert(Value::Num(0.0));
+                    }
+                    "branch" => branches.push(index),
+                    _ => continue,
+                },
+                "~" => match &block_keyword(&text)[..] {
+                    "while" | "repeat" => open_blocks.push(index),
+                    "endwhile" => match open_blocks.pop() {
+                        Some(start) if block_keyword(&self.lines[start]) == "while" => {
+                            self.blocks.insert(start, index);
+                            self.blocks.insert(index, start);
+                        }
+                        _ => errors.push(StoryError::UnmatchedBlock {
+                            line: index + 1,
+                            text,
+                        }),
+                    },
+                    "endrepeat" => match open_blocks.pop() {
+                        Some(start) if block_keyword(&self.lines[start]) == "repeat" => {
+                            self.blocks.insert(start, index);
+                            self.blocks.insert(index, start);
+                        }
+                        _ => errors.push(StoryError::UnmatchedBlock {
+                            line: index + 1,
+                            text,
+                        }),
+                    },
+                    "if" => open_blocks.push(index),
+                    //a false ~if jumps to its ~else, which then stands in for it until ~endif
+                    "else" => match open_blocks.pop() {
+                        Some(start) if block_keyword(&self.lines[start]) == "if" => {
+                            self.blocks.insert(start, index);
+                            open_blocks.push(index);
+                        }
+                        _ => errors.push(StoryError::UnmatchedBlock {
+                            line: index + 1,
+                            text,
+                        }),
+                    },
+                    "endif" => match open_blocks.pop() {
+                        Some(start)
+                            if matches!(&block_keyword(&self.lines[start])[..], "if" | "else") =>
+                        {
+                            self.blocks.insert(start, index);
+                        }
+                        _ => errors.push(StoryError::UnmatchedBlock {
+                            line: index + 1,
+                            text,
+                        }),
+                    },
+                    _ => continue,
+                },
+                _ => continue,
+            }
+        }
+
+        errors.extend(hotkey_number_conflicts(&hotkeys, &options));
+
+        for index in branches {
+            let text = self.lines[index].clone();
+            let malformed = |reason: String| StoryError::Malformed {
+                line: index + 1,
+                text: text.clone(),
+                reason,
+            };
+
+            //the labels are checked by validate, as streaming only holds one part at a time.
+            for token in split_directive(&text).1.split_whitespace() {
+                if let Err(reason) = parse_branch(token) {
+                    errors.push(malformed(reason));
+                }
+            }
+        }
+
+        for start in open_blocks {
+            errors.push(StoryError::UnmatchedBlock {
+                line: start + 1,
+                text: self.lines[start].clone(),
+            });
+        }
+
+        errors.extend(self.declare_defaults(declarations));
+
+        for name in optional {
+            self.variables.entry(name.clone()).or_default();
+            self.defaults.entry(name).or_default();
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    //reads the lines of a story into out with each *include replaced by the lines of its file.
+    //including holds the files being read in, outermost first, to catch files that include themselves.
+    fn read_story(
+        &mut self,
+        reader: impl BufRead,
+        dir: &Path,
+        namespace: Option<&str>,
+        including: &mut Vec<PathBuf>,
+        out: &mut Vec<String>,
+        errors: &mut Vec<StoryError>,
+    ) {
+        //lines of the story file itself are not from an included file.
+        let included = including.len() > usize::from(self.story_path.is_some());
+
+        for (n, line) in reader.lines().enumerate() {
+            //a line that is not UTF-8 stops the reading, as the lines after it can not be trusted.
+            let text = match line {
+                Ok(text) => text,
+                Err(why) => {
+                    errors.push(StoryError::Malformed {
+                        line: out.len() + 1,
+                        text: String::new(),
+                        reason: format!("it could not be read, {}", why),
+                    });
+                    break;
+                }
+            };
+            self.origins
+                .push((including.last().filter(|_| included).cloned(), n + 1));
+
+            if !is_include(&text) {
+                out.push(text);
+                continue;
+            }
+
+            let line = out.len() + 1;
+            let error = |path: &str, reason: String| StoryError::Include {
+                line,
+                path: path.to_string(),
+                reason,
+            };
+            //*include chapter1.txt as chapter1 keeps its labels apart from the rest of the story.
+            let (args, name_as) = match split_directive(&text).1.rsplit_once(" as ") {
+                Some((args, name)) => (args, Some(name.trim())),
+                None => (split_directive(&text).1, None),
+            };
+            let inner = match (namespace, name_as) {
+                (Some(outer), Some(name)) => Some(format!("{}.{}", outer, name)),
+                (None, Some(name)) => Some(name.to_string()),
+                (outer, None) => outer.map(String::from),
+            };
+
+            //kept as an empty line so errors point at the include itself.
+            out.push(String::new());
+
+            let name = match self.include_path(args) {
+                Ok(name) => name,
+                Err(reason) => {
+                    errors.push(error(args, reason));
+                    continue;
+                }
+            };
+
+            let path = dir.join(&name);
+            let key = self.file_key(&path);
+
+            if let Some(start) = including.iter().position(|p| *p == key) {
+                errors.push(StoryError::IncludeCycle {
+                    line,
+                    files: including[start..]
+                        .iter()
+                        .chain(Some(&key))
+                        .map(|p| p.display().to_string())
+                        .collect(),
+                });
+                continue;
+            }
+
+            match self.open_include(&path) {
+                Ok(reader) => {
+                    let dir = path.parent().unwrap_or(dir).to_path_buf();
+                    let start = out.len();
+                    including.push(key);
+                    self.read_story(reader, &dir, inner.as_deref(), including, out, errors);
+                    including.pop();
+
+                    if let (Some(name), Some(_)) = (inner, name_as) {
+                        self.namespaces.push((start..out.len(), name));
+                    }
+                }
+                Err(why) => errors.push(error(&path.display().to_string(), why.to_string())),
+            }
+        }
+    }
+
+    //a/../b.txt and b.txt are the same file, archives only have the one spelling.
+    fn file_key(&self, path: &Path) -> PathBuf {
+        match &self.archive {
+            Some(_) => path.to_path_buf(),
+            None => path.canonicalize().unwrap_or_else(|_| path.to_path_buf()),
+        }
+    }
+
+    //included files come out of the archive when the story was loaded from one.
+    fn open_include(&self, path: &Path) -> io::Result<Box<dyn BufRead>> {
+        match &self.archive {
+            Some(files) => match files.get(path) {
+                Some(data) => Ok(Box::new(Cursor::new(data.clone()))),
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "it is not in the archive",
+                )),
+            },
+            None => Ok(Box::new(BufReader::new(File::open(path)?))),
+        }
+    }
+
+    //loads entry out of a .zip or .tar.gz, with its includes looked up in the same archive.
+    pub fn load_from_archive(&mut self, path: &Path, entry: &str) -> Result<(), Vec<StoryError>> {
+        let error = |reason: String| {
+            vec![StoryError::Archive {
+                path: path.display().to_string(),
+                reason,
+            }]
+        };
+        let files = match read_archive(path) {
+            Ok(files) => files,
+            Err(why) => return Err(error(why.to_string())),
+        };
+        let entry = PathBuf::from(entry);
+        let data = match files.get(&entry) {
+            Some(data) => data.clone(),
+            None => return Err(error(format!("it has no {}", entry.display()))),
+        };
+
+        self.base_dir = entry.parent().map(Path::to_path_buf).unwrap_or_default();
+        self.story_path = Some(entry);
+        self.archive = Some(files);
+        self.processfile(Cursor::new(data))
+    }
+
+    //fills in @{name} in an include path from variables given with --var
+    fn include_path(&self, text: &str) -> Result<String, String> {
+        let mut out = String::new();
+        let mut rest = text;
+
+        while let Some(p) = rest.find("@{") {
+            let end = match rest[p..].find('}') {
+                Some(e) => p + e,
+                None => return Err(String::from("@{ has no closing }")),
+            };
+
+            let name = rest[p + 2..end].trim();
+
+            match self.variables.get(name) {
+                Some(value) => {
+                    out.push_str(&rest[..p]);
+                    out.push_str(&value.to_string());
+                }
+                None => {
+                    return Err(format!(
+                        "@{} is not set, give it with --var {}=value",
+                        name, name
+                    ))
+                }
+            }
+
+            rest = &rest[end + 1..];
+        }
+
+        out.push_str(rest);
+        Ok(out.trim().to_string())
+    }
+
+    //names are all known by now, so starting values are worked out with the ones they use first.
+    fn declare_defaults(&mut self, declarations: Vec<(String, String, usize)>) -> Vec<StoryError> {
+        let sources: HashMap<String, (String, usize)> = declarations
+            .iter()
+            .map(|(name, r, line)| (name.clone(), (r.clone(), *line)))
+            .collect();
+        let mut errors = Vec::new();
+        let mut order = Vec::new();
+        let mut done = HashSet::new();
+
+        for (name, _, _) in &declarations {
+            if let Err(e) = default_order(name, &sources, &mut done, &mut Vec::new(), &mut order) {
+                //the variables in the cycle keep 0 so each cycle is only reported once.
+                if let StoryError::CyclicDefault { names, .. } = &e {
+                    done.extend(names.iter().cloned());
+                }
+
+                errors.push(e);
+            }
+        }
+
+        for name in order {
+            let (r, line) = &sources[&name];
+            self.index = *line;
+
+            match self.evaluate(&name, r) {
+                Ok(value) => {
+                    let value = Value::infer(&value);
+                    self.variables.insert(name.clone(), value.clone());
+                    self.defaults.insert(name, value);
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+
+        self.index = 0;
+        errors
+    }
+
+    //comments come off narrative as well as commands, Hello there  // greeting shows Hello there.
+    //the comment itself is handed back too, it becomes the description of a variable.
+    fn strip_comment(&self, text: String) -> (String, Option<String>) {
+        let mut quoted = false;
+
+        for (i, c) in text.char_indices() {
+            if c == '"' {
+                quoted = !quoted;
+            } else if !quoted && comment_can_start(&text, i) {
+                if let Some(m) = self
+                    .comment_markers
+                    .iter()
+                    .find(|m| !m.is_empty() && text[i..].starts_with(&m[..]))
+                {
+                    let comment = text[i + m.len()..].trim().to_string();
+                    return (text[..i].trim_end().to_string(), Some(comment));
+                }
+            }
+        }
+
+        (text, None)
+    }
+
+    fn process_variables(&self, text: &str) -> Result<String, StoryError> {
+        //\@ is a plain @, like mail\@example.com, so the text either side is filled in on its own.
+        if text.contains("\\@") {
+            let parts = text
+                .split("\\@")
+                .map(|part| self.process_variables(part))
+                .collect::<Result<Vec<String>, StoryError>>()?;
+            return Ok(parts.join("@"));
+        }
+
+        let text = &self.process_formats(text)?;
+        let mut s = String::from(text);
+
+        for item in parse_variables(text).iter() {
+            if !text.is_empty() {
+                let var = self.variable_value(item)?;
+                s = replace_variable(&s, item, &var);
+            }
+        }
+        Ok(s)
+    }
+
+    fn variable_value(&self, name: &str) -> Result<String, StoryError> {
+        match self.variables.get(name) {
+            Some(v) => Ok(v.to_string()),
+            None => match self.lists.get(name) {
+                Some(list) => Ok(list.join(", ")),
+                None => Err(StoryError::MissingVariable {
+                    name: name.to_string(),
+                    line: self.index + 1,
+                }),
+            },
+        }
+    }
+
+    //fills in {{@name:spec}} with the value padded to the spec, like {{@gold:>6}} or {{@name:-<10}}
+    fn process_formats(&self, text: &str) -> Result<String, StoryError> {
+        let mut out = String::new();
+        let mut rest = text;
+
+        while let Some(p) = rest.find("{{@") {
+            let end = match rest[p..].find("}}") {
+                Some(e) => p + e,
+                None => break,
+            };
+
+            let inner = &rest[p + 3..end];
+            let (name, spec) = match inner.find(':') {
+                Some(c) => (&inner[..c], &inner[c + 1..]),
+                None => (inner, ""),
+            };
+
+            out.push_str(&rest[..p]);
+            out.push_str(&format_spec(&self.variable_value(name.trim())?, spec));
+            rest = &rest[end + 2..];
+        }
+
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    //@hp > 0 && @gold < 100 || @cheat, where && binds tighter than || and each stops as soon as
+    //the answer is known. Brackets around a whole part group it, like (@a || @b) && @c.
+    fn process_expression(&self, text: String) -> Result<bool, StoryError> {
+        for any in split_top_level(&text, "||") {
+            let mut all = true;
+
+            for part in split_top_level(any, "&&") {
+                let holds = match strip_group(part) {
+                    Some(inner) => self.process_expression(inner.to_string())?,
+                    None => self.process_comparison(part.to_string())?,
+                };
+
+                if !holds {
+                    all = false;
+                    break;
+                }
+            }
+
+            if all {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    //each side is a number, true or false, or text, and only sides of the same kind compare,
+    //so 10 < 9 is about numbers and "10" == 10 is an error instead of quietly being false.
+    fn process_comparison(&self, text: String) -> Result<bool, StoryError> {
+        let (left, mid, right) = self.get_expression(text)?;
+
+        if mid.is_empty() {
+            let value = self.operand(&left)?;
+            return Ok(self.is_truthy(&value));
+        }
+
+        let (lvalue, rvalue) = (self.operand(&left)?, self.operand(&right)?);
+
+        if self.strict_numbers {
+            for (side, value) in [(&left, &lvalue), (&right, &rvalue)].iter() {
+                if value.as_number().is_none() {
+                    return Err(StoryError::NotANumber {
+                        operand: side.trim().to_string(),
+                        line: self.index + 1,
+                    });
+                }
+            }
+        }
+
+        let mismatch = || StoryError::TypeMismatch {
+            left: left.trim().to_string(),
+            right: right.trim().to_string(),
+            kinds: (lvalue.kind(), rvalue.kind()),
+            op: mid.clone(),
+            line: self.index + 1,
+        };
+
+        let same = match (&lvalue, &rvalue) {
+            (Value::Num(l), Value::Num(r)) => match &mid[..] {
+                "<=" => return Ok(l <= r),
+                ">=" => return Ok(l >= r),
+                "<" => return Ok(l < r),
+                ">" => return Ok(l > r),
+                _ => l.approx_eq(
+                    *r,
+                    float_cmp::F64Margin {
+                        ulps: 0,
+                        epsilon: self.epsilon,
+                    },
+                ),
+            },
+            _ if !matches!(&mid[..], "==" | "!=") => return Err(mismatch()),
+            (Value::Str(l), Value::Str(r)) => l == r,
+            (Value::Bool(l), Value::Bool(r)) => l == r,
+            //lenient stories compare as text, so a missing variable's 0 is never "yes"
+            _ if self.lenient => false,
+            _ => return Err(mismatch()),
+        };
+
+        Ok(match &mid[..] {
+            "==" => same,
+            _ => !same,
+        })
+    }
+
+    //the test of an !if, ~while or ternary with its variables and functions filled in.
+    fn process_condition(&self, text: &str) -> Result<bool, StoryError> {
+        let mut text = text.to_string();
+
+        if self.lenient {
+            for item in parse_variables(&text) {
+                if !self.variables.contains_key(&item) && !self.lists.contains_key(&item) {
+                    text = replace_variable(&text, &item, "0");
+                }
+            }
+        }
+
+        self.process_expression(text)
+    }
+
+    //one side of a comparison, text has its quotes taken off so "Bob" matches Bob.
+    //a lone @variable is the value it holds, so an answer of 10 to ^s is still text.
+    fn operand(&self, side: &str) -> Result<Value, StoryError> {
+        let side = side.trim();
+
+        if let Some(name) = side.strip_prefix('@') {
+            match self.variables.get(name) {
+                Some(Value::Str(s)) if parse_variables(side) == [name] => {
+                    return Ok(Value::Str(unquote(s)))
+                }
+                Some(value) if parse_variables(side) == [name] => return Ok(value.clone()),
+                _ => {}
+            }
+        }
+
+        let side = self.process_functions(side)?;
+        let side = side.trim();
+
+        Ok(match self.expressions.eval(side, &self.variables) {
+            Ok(v) => v,
+            Err(_) => match side {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                _ => Value::Str(unquote(side)),
+            },
+        })
+    }
+
+    //a value on its own in a condition is true unless it is zero, empty or false.
+    fn is_truthy(&self, value: &Value) -> bool {
+        match value {
+            Value::Num(n) => *n != 0.0,
+            Value::Bool(b) => *b,
+            Value::Str(s) => !s.is_empty() && !s.eq_ignore_ascii_case("false"),
+        }
+    }
+
+    fn get_expression(&self, text: String) -> Result<(String, String, String), StoryError> {
+        let re = Regex::new(r"!=|==|<=|>=|<|>").unwrap();
+        let mut mid = String::new();
+
+        let mut part = re.captures_iter(&text[..]);
+
+        //no operator means the whole text is tested on its own.
+        match part.next() {
+            Some(c) => mid.push_str(&c[0]),
+            None => return Ok((text, mid, String::new())),
+        }
+
+        let arr: Vec<&str> = text.split(&mid[..]).collect();
+
+        if arr.len() != 2 {
+            return Err(StoryError::Malformed {
+                line: self.index + 1,
+                text: self.lines[self.index].clone(),
+                reason: format!(
+                    "{} should be one comparison, with a left side, an operator and a right side",
+                    text.trim()
+                ),
+            });
+        }
+
+        Ok((String::from(arr[0]), mid, String::from(arr[1])))
+    }
+
+    fn tokenize(&self, line: String, pat: &str) -> Result<(String, String), String> {
+        let arr: Vec<&str> = line.split(pat).collect();
+
+        if arr.len() != 2 {
+            return Err(format!(
+                "The Token {} contained {} but should have only 2 at line {}.
+            It should be seperated by {}",
+                line,
+                arr.len(),
+                self.index,
+                pat,
+            ));
+        }
+
+        //spaces around the delimiter are for readability, so @gold = 5 names gold not "gold ".
+        let mut iter = arr.iter().map(|part| part.trim());
+        Ok((
+            String::from(iter.next().expect("expected 2 names, got 0")),
+            String::from(iter.next().expect("expected 2 names, got 1")),
+        ))
+    }
+
+    //splits a ! line like !cond1:action1:cond2:action2:else into each condition with its action,
+    //then the else if there is one. The ! is taken off the first condition.
+    fn iftokenize(&self, line: String, pat: &str) -> Result<(IfBranches, Option<String>), String> {
+        let arr: Vec<&str> = split_if(&line, pat).into_iter().map(str::trim).collect();
+
+        if arr.len() < 2 {
+            return Err(format!(
+                "The Token {} contained {} but should have 2 or more parts at line {}.
+            It should be seperated by {}",
+                line,
+                arr.len(),
+                self.index + 1,
+                pat,
+            ));
+        }
+
+        let mut branches: Vec<(String, String)> = arr
+            .chunks(2)
+            .filter(|pair| pair.len() == 2)
+            .map(|pair| (pair[0].to_string(), pair[1].to_string()))
+            .collect();
+        branches[0].0 = branches[0].0.trim_start_matches('!').trim().to_string();
+
+        //an odd number of parts leaves the else on its own at the end.
+        let other = match arr.len() % 2 {
+            1 => arr.last().map(|s| s.to_string()),
+            _ => None,
+        };
+
+        Ok((branches, other))
+    }
+
+    //checks the conditions and assigned values on a line when the story loads, so a typo like
+    //@gold = @gold + * 2 is found before a player gets there instead of being kept as text.
+    fn broken_expression_in(&self, text: &str) -> Option<String> {
+        let engine = self.expressions.as_ref();
+
+        match text.chars().next()? {
+            '!' => {
+                let (branches, _) = self.iftokenize(text.to_string(), ":").ok()?;
+                branches
+                    .iter()
+                    .find_map(|(cond, _)| broken_condition(engine, cond))
+            }
+            '~' => {
+                let args = text[1..].trim_start();
+
+                match &block_keyword(text)[..] {
+                    "if" => broken_condition(engine, &args[2..]),
+                    "while" => {
+                        let args = &args[5..];
+                        broken_condition(
+                            engine,
+                            args.rsplit_once(" limit ").map_or(args, |(c, _)| c),
+                        )
+                    }
+                    "repeat" => broken_value(engine, &args[6..]),
+                    _ => None,
+                }
+            }
+            '@' => match split_assignment(text) {
+                Some((_, r)) if list_call(&r, "split").is_none() => broken_value(engine, &r),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    //labels are stored under this name so lookups can ignore case when asked to.
+    fn label_key(&self, name: &str) -> String {
+        if self.case_insensitive_labels {
+            name.to_lowercase()
+        } else {
+            name.to_string()
+        }
+    }
+
+    //the text shown after :label up to the next label, goto or question, without running anything.
+    pub fn render_label(&self, label: &str) -> Result<String, StoryError> {
+        let start = self
+            .label_index(label)
+            .ok_or_else(|| StoryError::UnknownLabel {
+                label: label.to_string(),
+            })?;
+        let mut out = Vec::new();
+
+        for text in &self.lines[start + 1..] {
+            match text.chars().next() {
+                Some(':' | '#' | '?') => break,
+                Some('|') => out.push(String::new()),
+                Some('@') => {
+                    if let Some((speaker, said)) = split_dialogue(text) {
+                        let line = format!("[color bold]{}:[/color] {}", speaker, said);
+                        out.push(self.render_markup(&self.process_variables(&line)?));
+                    }
+                }
+                Some(_) if !is_command(text) => {
+                    out.push(self.render_markup(&self.process_variables(text)?))
+                }
+                _ => {}
+            }
+        }
+
+        Ok(out.join("\n"))
+    }
+
+    //where the lines after :label can lead, as (choice or condition, label) pairs, without running them.
+    pub fn outgoing_edges(&self, from_label: &str) -> Vec<(String, String)> {
+        match self.label_index(from_label) {
+            Some(start) => self.block_edges(start).0,
+            None => Vec::new(),
+        }
+    }
+
+    //the edges of the block starting on line start, and the line it runs on into when it does
+    //not always jump away, the next label or the end of the story.
+    fn block_edges(&self, start: usize) -> (Vec<(String, String)>, Option<usize>) {
+        let mut edges = Vec::new();
+        let first = match self.lines.get(start) {
+            Some(text) if text.starts_with(':') => start + 1,
+            _ => start,
+        };
+
+        for (i, text) in self.lines.iter().enumerate().skip(first) {
+            if self.is_preformatted(i) {
+                continue;
+            }
+
+            match text.chars().next() {
+                Some(':') => return (edges, Some(i)),
+                Some('#') => {
+                    edges.push((String::new(), text[1..].trim().to_string()));
+                    return (edges, None);
+                }
+                Some('?') => {
+                    if let Ok((left, right)) = self.tokenize(text.clone(), ":") {
+                        let (_, option) = parse_correct(&left[1..]);
+                        let (_, option) = parse_hotkey(option);
+                        edges.push((option.trim().to_string(), right.trim().replace("#", "")));
+                    }
+                }
+                Some('*') if split_directive(text).0 == "branch" => {
+                    for token in split_directive(text).1.split_whitespace() {
+                        if let Ok((label, _, cond)) = parse_branch(token) {
+                            let choice = cond.map(|c| format!("random if {}", c));
+                            let choice = choice.unwrap_or_else(|| String::from("random"));
+                            edges.push((choice, label.to_string()));
+                        }
+                    }
+                }
+                Some('!') => {
+                    if let Ok((branches, other)) = self.iftokenize(text.clone(), ":") {
+                        for (cond, then) in &branches {
+                            if let Some(label) = then.strip_prefix('#') {
+                                edges.push((cond.clone(), label.trim().to_string()));
+                            }
+                        }
+
+                        let otherwise = match &branches[..] {
+                            [(cond, _)] => format!("not {}", cond),
+                            _ => String::from("otherwise"),
+                        };
+
+                        if let Some(label) = other.as_deref().and_then(|o| o.strip_prefix('#')) {
+                            edges.push((otherwise, label.trim().to_string()));
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            //nothing after a question block runs, the player always goes to one of its labels.
+            if text.starts_with('?')
+                && !matches!(self.lines.get(i + 1), Some(l) if l.starts_with('?'))
+            {
+                return (edges, None);
+            }
+        }
+
+        (edges, Some(self.lines.len()))
+    }
+
+    //labels whose block runs on to the end of the story or has an *ending line, in story order,
+    //each with whether the player can get there from the start.
+    pub fn endings(&self) -> Vec<(String, bool)> {
+        let mut labels: Vec<(&String, &usize)> = self.labels.iter().collect();
+        labels.sort_by_key(|(_, line)| **line);
+
+        //every line a block can start on that the player can get to, found from the top.
+        let mut reached = HashSet::new();
+        let mut todo = vec![0];
+
+        while let Some(start) = todo.pop() {
+            if !reached.insert(start) {
+                continue;
+            }
+
+            let (edges, next) = self.block_edges(start);
+            todo.extend(next.filter(|next| *next < self.lines.len()));
+
+            for (_, label) in edges {
+                if let Some(line) = self.resolve_label(&label, start) {
+                    todo.push(line);
+                }
+            }
+        }
+
+        labels
+            .into_iter()
+            .filter(|(_, start)| {
+                let marked = self.lines[**start + 1..]
+                    .iter()
+                    .take_while(|text| !text.starts_with(':'))
+                    .any(|text| text.starts_with('*') && split_directive(text).0 == "ending");
+
+                marked || self.block_edges(**start).1 == Some(self.lines.len())
+            })
+            .map(|(name, start)| (name.clone(), reached.contains(start)))
+            .collect()
+    }
+
+    //every goto, question option, if and *branch that leads to a label the story does not have, so
+    //a typo is found before playing rather than when the line is reached.
+    pub fn validate(&self) -> Result<(), Vec<StoryError>> {
+        let mut errors = Vec::new();
+
+        for (index, text) in self.lines.iter().enumerate() {
+            let mut targets = Vec::new();
+
+            if self.is_preformatted(index) {
+                continue;
+            }
+
+            match text.chars().next() {
+                Some('#' | '>') => targets.push(text[1..].to_string()),
+                Some('?') => {
+                    if let Ok((_, right)) = self.tokenize(text.clone(), ":") {
+                        targets.push(right);
+                    }
+                }
+                Some('!') => {
+                    if let Ok((branches, other)) = self.iftokenize(text.clone(), ":") {
+                        let actions = branches.into_iter().map(|(_, then)| then).chain(other);
+                        targets.extend(actions.filter(|action| action.starts_with('#')));
+                    }
+                }
+                Some('*') if split_directive(text).0 == "branch" => targets.extend(
+                    split_directive(text)
+                        .1
+                        .split_whitespace()
+                        .filter_map(|token| parse_branch(token).ok())
+                        .map(|(label, _, _)| label.to_string()),
+                ),
+                _ => {}
+            }
+
+            for target in targets {
+                let label = unquote(target.replace("#", "").replace(":", "").trim());
+
+                //#@next is only known once it runs, #menu can go back to the last question.
+                if label.contains('@') || (label == "menu" && self.label_index("menu").is_none()) {
+                    continue;
+                }
+
+                if self.resolve_label(&label, index).is_none() {
+                    errors.push(StoryError::Malformed {
+                        line: index + 1,
+                        text: text.clone(),
+                        reason: format!("there is no label :{}", label),
+                    });
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    //lines between *preformatted and *endpre are shown as they are, never run.
+    fn is_preformatted(&self, line: usize) -> bool {
+        self.blocks.iter().any(|(start, end)| {
+            *start < line && line < *end && split_directive(&self.lines[*start]).0 == "preformatted"
+        })
+    }
+
+    //every @variable a line uses that the story never creates, which would stop it once reached.
+    pub fn missing_variables(&self) -> Vec<StoryError> {
+        let mut errors = Vec::new();
+
+        for (index, text) in self.lines.iter().enumerate() {
+            if self.is_preformatted(index) || text.starts_with("^l:") {
+                continue;
+            }
+
+            //the name being assigned and the speaker of dialogue are not uses.
+            let used = match text.starts_with('@') {
+                true => match (split_assignment(text), split_dialogue(text)) {
+                    (Some((_, r)), _) => r,
+                    (_, Some((_, said))) => said.to_string(),
+                    _ => text.clone(),
+                },
+                false => text.clone(),
+            };
+
+            for name in parse_variables(&used.replace("\\@", "")) {
+                if !self.variables.contains_key(&name) && !self.lists.contains_key(&name) {
+                    errors.push(StoryError::MissingVariable {
+                        name,
+                        line: index + 1,
+                    });
+                }
+            }
+        }
+
+        errors
+    }
+
+    //the error followed by where its line really is and what it says, since the lines of
+    //*include files are counted into the story's and would not match the file being edited.
+    pub fn explain(&self, error: &StoryError) -> String {
+        let message = error.to_string();
+        let line = match error.line() {
+            Some(line) if line >= 1 && line <= self.lines.len() => line,
+            _ => return message,
+        };
+        let text = self.lines[line - 1].trim();
+
+        match self.origins.get(line - 1) {
+            Some((Some(file), n)) => {
+                format!(
+                    "{}\n  in {} on line {}: {}",
+                    message,
+                    file.display(),
+                    n,
+                    text
+                )
+            }
+            //lines after an *include are further down the story than in the file.
+            Some((None, n)) if *n != line => {
+                format!("{}\n  in the story file on line {}: {}", message, n, text)
+            }
+            _ if text.is_empty() || message.contains(text) => message,
+            _ => format!("{}\n  line {}: {}", message, line, text),
+        }
+    }
+
+    pub fn variable(&self, name: &str) -> Option<&Value> {
+        self.variables.get(name)
+    }
+
+    //sets a variable before the story is loaded, like --var does, so it also starts out with it.
+    pub fn set_variable(&mut self, name: &str, value: Value) {
+        self.variables.insert(name.to_string(), value.clone());
+        self.defaults.insert(name.to_string(), value);
+    }
+
+    pub fn list(&self, name: &str) -> Option<&Vec<String>> {
+        self.lists.get(name)
+    }
+
+    pub fn label_index(&self, name: &str) -> Option<usize> {
+        self.labels.get(&self.label_key(name)).copied()
+    }
+
+    //the namespace of the innermost *include ... as name that line came from.
+    fn namespace_at(&self, line: usize) -> Option<&str> {
+        self.namespaces
+            .iter()
+            .filter(|(lines, _)| lines.contains(&line))
+            .min_by_key(|(lines, _)| lines.len())
+            .map(|(_, name)| name.as_str())
+    }
+
+    //a label's full name, as written on line.
+    fn qualified(&self, name: &str, line: usize) -> String {
+        match self.namespace_at(line) {
+            Some(namespace) => format!("{}.{}", namespace, name),
+            None => name.to_string(),
+        }
+    }
+
+    //a goto on line finds a label of its own file first, then one by its full name,
+    //so #intro stays in chapter1 and #chapter2.intro reaches into chapter2.
+    fn resolve_label(&self, name: &str, line: usize) -> Option<usize> {
+        self.label_index(&self.qualified(name, line))
+            .or_else(|| self.label_index(name))
+    }
+
+    fn advancing(&self) -> bool {
+        self.advance_to.is_some()
+    }
+
+    fn check_advance(&mut self) {
+        if let Some(label) = &self.advance_to {
+            if self.label_index(label) == Some(self.index) {
+                self.advance_to = None;
+            }
+        }
+    }
+
+    fn handle_block(&mut self) -> Result<(), StoryError> {
+        let text = self.lines[self.index].clone();
+
+        match &block_keyword(&text)[..] {
+            "while" => self.process_while(text[1..].trim_start()[5..].trim()),
+            "endwhile" | "endrepeat" => {
+                self.index = self.blocks[&self.index];
+                Ok(())
+            }
+            "repeat" => self.process_repeat(text[1..].trim_start()[6..].trim()),
+            //the whole skipped branch is jumped over, questions and all.
+            "if" => {
+                match self.process_condition(text[1..].trim_start()[2..].trim())? {
+                    true => self.index += 1,
+                    false => self.index = self.blocks[&self.index] + 1,
+                }
+
+                Ok(())
+            }
+            //reached at the end of a true branch, so the else branch is skipped.
+            "else" => {
+                self.index = self.blocks[&self.index] + 1;
+                Ok(())
+            }
+            "endif" => {
+                self.index += 1;
+                Ok(())
+            }
+            _ => {
+                self.input_wait();
+                Ok(())
+            }
+        }
+    }
+
+    fn process_while(&mut self, args: &str) -> Result<(), StoryError> {
+        let (cond, limit) = match args.rfind(" limit ") {
+            Some(p) => match usize::from_str(args[p + 7..].trim()) {
+                Ok(n) => (&args[..p], n),
+                Err(_) => (args, self.loop_limit),
+            },
+            None => (args, self.loop_limit),
+        };
+
+        if self.process_condition(cond)? {
+            let count = self.loop_counts.entry(self.index).or_insert(0);
+            *count += 1;
+
+            if *count > limit {
+                return Err(StoryError::LoopLimit {
+                    line: self.index + 1,
+                    limit,
+                });
+            }
+
+            self.index += 1;
+        } else {
+            //reset so the loop gets its full limit the next time it is reached.
+            self.loop_counts.remove(&self.index);
+            self.index = self.blocks[&self.index] + 1;
+        }
+
+        Ok(())
+    }
+
+    //~repeat 3 works out its count once on the way in, loop_counts then holds how many runs are left.
+    fn process_repeat(&mut self, args: &str) -> Result<(), StoryError> {
+        if !self.loop_counts.contains_key(&self.index) {
+            let value = self.evaluate_value(args)?;
+            let count = match f64::from_str(value.trim()) {
+                Ok(n) if n >= 0.0 => n as usize,
+                Ok(_) => {
+                    return Err(StoryError::Malformed {
+                        line: self.index + 1,
+                        text: self.lines[self.index].clone(),
+                        reason: format!("~repeat can not run {} times", value.trim()),
+                    })
+                }
+                Err(_) => {
+                    return Err(StoryError::NotANumber {
+                        operand: value.trim().to_string(),
+                        line: self.index + 1,
+                    })
+                }
+            };
+
+            if count > self.loop_limit {
+                return Err(StoryError::LoopLimit {
+                    line: self.index + 1,
+                    limit: self.loop_limit,
+                });
+            }
+
+            self.loop_counts.insert(self.index, count);
+        }
+
+        match self.loop_counts.get_mut(&self.index) {
+            Some(left) if *left > 0 => {
+                *left -= 1;
+                self.index += 1;
+            }
+            _ => {
+                self.loop_counts.remove(&self.index);
+                self.index = self.blocks[&self.index] + 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    //replaces calls to the story's built in functions with their results, innermost first.
+    fn process_functions(&self, text: &str) -> Result<String, StoryError> {
+        let mut out = String::new();
+        let mut rest = text;
+
+        while let Some((start, name)) = find_function(rest) {
+            let open = start + name.len();
+            let close = match matching_paren(&rest[open..]) {
+                Some(c) => open + c,
+                None => {
+                    return Err(StoryError::Function {
+                        name: name.to_string(),
+                        message: String::from("is missing its closing )"),
+                        line: self.index + 1,
+                    })
+                }
+            };
+
+            //the arguments are split before any variable is filled in, so a value with a comma stays one.
+            let args = split_args(&rest[open + 1..close])
+                .iter()
+                .map(|arg| self.argument(arg))
+                .collect::<Result<Vec<String>, StoryError>>()?;
+            out.push_str(&self.process_variables(&rest[..start])?);
+            out.push_str(&self.call_function(name, args)?);
+            rest = &rest[close + 1..];
+        }
+
+        out.push_str(&self.process_variables(rest)?);
+        Ok(out)
+    }
+
+    //works out one function argument, which is quoted text, a variable or anything else with calls in it.
+    //only the spaces around the argument are trimmed, so len and trim see the value as it was written.
+    fn argument(&self, arg: &str) -> Result<String, StoryError> {
+        let arg = arg.trim();
+
+        if arg.len() >= 2 && arg.starts_with('"') && arg.ends_with('"') {
+            return self.process_variables(&unquote(arg));
+        }
+
+        if let Some(name) = arg.strip_prefix('@') {
+            if parse_variables(arg) == [name] {
+                return Ok(unquote(&self.variable_value(name)?));
+            }
+        }
+
+        if let Some((0, name)) = find_function(arg) {
+            if matching_paren(&arg[name.len()..]) == Some(arg.len() - name.len() - 1) {
+                return self.process_functions(arg);
+            }
+        }
+
+        Ok(unquote(self.process_functions(arg)?.trim()))
+    }
+
+    fn call_function(&self, name: &str, args: Vec<String>) -> Result<String, StoryError> {
+        let line = self.index + 1;
+        let error = |message: String| StoryError::Function {
+            name: name.to_string(),
+            message,
+            line,
+        };
+        let expect = |count: usize| {
+            if args.len() == count {
+                Ok(())
+            } else {
+                Err(error(format!(
+                    "takes {} arguments but got {}",
+                    count,
+                    args.len()
+                )))
+            }
+        };
+        let number = |arg: &str| match self.expressions.eval(arg, &self.variables) {
+            Ok(Value::Num(n)) => Ok(n),
+            _ => Err(error(format!("expected a number but got {}", arg))),
+        };
+        let base = |arg: &str| match number(arg)? as u32 {
+            b @ 2..=36 => Ok(b),
+            b => Err(error(format!("base {} must be from 2 to 36", b))),
+        };
+
+        match name {
+            "visited" => {
+                expect(1)?;
+                Ok(bool_string(
+                    self.visited.contains(&self.label_key(&args[0])),
+                ))
+            }
+            "elapsed" => {
+                expect(0)?;
+                Ok(self.elapsed().as_secs_f64().to_string())
+            }
+            "tobase" => {
+                expect(2)?;
+                Ok(to_base(number(&args[0])? as i64, base(&args[1])?))
+            }
+            "frombase" => {
+                expect(2)?;
+                let radix = base(&args[1])?;
+
+                match i64::from_str_radix(args[0].trim(), radix) {
+                    Ok(v) => Ok(v.to_string()),
+                    Err(_) => Err(error(format!(
+                        "{} is not a valid base {} number",
+                        args[0], radix
+                    ))),
+                }
+            }
+            //a whole number from min to max, both included.
+            "rand" => {
+                expect(2)?;
+                let (min, max) = (number(&args[0])? as i64, number(&args[1])? as i64);
+
+                if min > max {
+                    return Err(error(format!("min {} is more than max {}", min, max)));
+                }
+
+                Ok(self.rng.borrow_mut().gen_range(min..=max).to_string())
+            }
+            //a decimal from min up to but not including max, like randf(0,1) for a chance.
+            "randf" => {
+                expect(2)?;
+                let (min, max) = (number(&args[0])?, number(&args[1])?);
+
+                if min >= max {
+                    return Err(error(format!("min {} must be less than max {}", min, max)));
+                }
+
+                Ok(self.rng.borrow_mut().gen_range(min..max).to_string())
+            }
+            //approx(@x, 0.3, 0.01) is true when the numbers are no further apart than the last.
+            "approx" => {
+                expect(3)?;
+                let (a, b, epsilon) = (number(&args[0])?, number(&args[1])?, number(&args[2])?);
+
+                if epsilon < 0.0 {
+                    return Err(error(format!("{} can not be below 0", epsilon)));
+                }
+
+                Ok(bool_string((a - b).abs() <= epsilon))
+            }
+            //true about percent times in a hundred, like !chance(30):#ambush
+            "chance" => {
+                expect(1)?;
+                let percent = number(&args[0])?;
+
+                if !(0.0..=100.0).contains(&percent) {
+                    return Err(error(format!("{} is not a percent from 0 to 100", percent)));
+                }
+
+                Ok(bool_string(
+                    self.rng.borrow_mut().gen_range(0.0..100.0) < percent,
+                ))
+            }
+            //every match of old is replaced, like replace(@name, "_", " ")
+            "replace" => {
+                expect(3)?;
+
+                if args[1].is_empty() {
+                    return Err(error(String::from(
+                        "needs text to search for that is not empty",
+                    )));
+                }
+
+                Ok(args[0].replace(&args[1], &args[2]))
+            }
+            "trim" => {
+                expect(1)?;
+                Ok(args[0].trim().to_string())
+            }
+            //counted in letters, not bytes, so len(Zoë) is 3.
+            "len" => {
+                expect(1)?;
+                Ok(args[0].chars().count().to_string())
+            }
+            "upper" => {
+                expect(1)?;
+                Ok(args[0].to_uppercase())
+            }
+            "lower" => {
+                expect(1)?;
+                Ok(args[0].to_lowercase())
+            }
+            //substr(@name, 0, 3) is the first 3 letters, cut short at the end of the text.
+            "substr" => {
+                expect(3)?;
+                let (start, count) = (number(&args[1])?, number(&args[2])?);
+
+                if start < 0.0 || count < 0.0 {
+                    return Err(error(format!(
+                        "start {} and count {} can not be below 0",
+                        start, count
+                    )));
+                }
+
+                Ok(args[0]
+                    .chars()
+                    .skip(start as usize)
+                    .take(count as usize)
+                    .collect())
+            }
+            //runs of spaces inside the text become one, and the ends are trimmed.
+            "squeeze" => {
+                expect(1)?;
+                Ok(args[0].split_whitespace().collect::<Vec<_>>().join(" "))
+            }
+            _ => Err(error(String::from("is not a known function"))),
+        }
+    }
+
+    //runs the line at the current index and moves on to the next one to run.
+    pub fn step(&mut self) -> Result<StepResult, StoryError> {
+        if self.started.is_none() {
+            self.started = Some(self.clock.now());
+        }
+
+        if self.index >= self.lines.len() {
+            if self.pause_on_finish && !self.finished && !self.advancing() {
+                self.prompt("The End. Press Enter to exit.");
+                self.io.read_line();
+            }
+
+            self.finished = true;
+            return Ok(StepResult::Finished);
+        }
+
+        let line = self.index + 1;
+
+        //streamed parts count their lines from each label, so breakpoints would land anywhere.
+        if self.breakpoints.contains(&line) && !self.streaming && self.paused_at != Some(line) {
+            self.paused_at = Some(line);
+            return Ok(StepResult::Breakpoint(line));
+        }
+
+        self.paused_at = None;
+        self.steps += 1;
+
+        //a goto that jumps back forever would otherwise hang without showing anything.
+        if let Some(limit) = self.max_steps.filter(|limit| self.steps > *limit) {
+            return Err(StoryError::StepLimitExceeded { line, limit });
+        }
+
+        self.check_advance();
+        self.update_timer();
+        let text = self.lines[self.index].clone();
+
+        if text.is_empty() {
+            self.index += 1;
+            return Ok(StepResult::Continue);
+        }
+
+        //narrative may start with a letter that takes more than one byte, like É
+        match text.get(0..1).unwrap_or_default() {
+            ":" => self.handle_label(&text[1..])?,
+            "\n" | "\r" => self.index += 1,
+            "*" => self.handle_directive(&text)?,
+            "|" => {
+                self.emit(OutputEvent::Narrative(String::new()));
+                self.index += 1;
+            }
+            "#" => self.handle_goto(None)?,
+            ">" => self.handle_gosub(&text[1..])?,
+            "<" => self.handle_return()?,
+            "!" => self.handle_if()?,
+            "@" => self.handle_assign(None)?,
+            "?" => self.handle_question()?,
+            "^" => self.handle_input()?,
+            "~" => self.handle_block()?,
+            "$" if matches!(&split_directive(&text).0[..], "save" | "load") => {
+                self.handle_state(&text)?
+            }
+            "`" => self.clear_screen(),
+            _ => self.handle_text(&text)?,
+        }
+
+        Ok(StepResult::Continue)
+    }
+
+    fn handle_label(&mut self, name: &str) -> Result<(), StoryError> {
+        let (checkpoint, name) = parse_checkpoint(name);
+        let name = &self.qualified(name, self.index);
+
+        if !self.advancing()
+            && self.death_label.as_deref().map(|d| self.label_key(d)) == Some(self.label_key(name))
+        {
+            self.rewind();
+            return Ok(());
+        }
+
+        self.visited.insert(self.label_key(name));
+        self.last_label = Some(name.trim().to_string());
+
+        //saved on the label itself so continuing passes through it again.
+        if checkpoint {
+            self.checkpoint();
+        }
+
+        self.index += 1;
+        Ok(())
+    }
+
+    fn handle_text(&mut self, text: &str) -> Result<(), StoryError> {
+        self.printmove(text)
+    }
+
+    //*timer start 30 counts @_timer down from 30 seconds, *timer stop leaves it where it is.
+    fn process_timer(&mut self, args: &str) -> Result<(), StoryError> {
+        let malformed = |reason: String| StoryError::Malformed {
+            line: self.index + 1,
+            text: self.lines[self.index].clone(),
+            reason,
+        };
+        let (action, secs) = split_timer(args).map_err(malformed)?;
+
+        match action {
+            "start" => {
+                let secs = self.process_variables(secs)?;
+                let secs = match f64::from_str(secs.trim()) {
+                    Ok(n) if n >= 0.0 => n,
+                    _ => {
+                        return Err(malformed(format!(
+                            "{} is not a number of seconds",
+                            secs.trim()
+                        )))
+                    }
+                };
+
+                self.timer_end = Some(self.clock.now() + Duration::from_secs_f64(secs));
+                self.update_timer();
+            }
+            _ => {
+                self.update_timer();
+                self.timer_end = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    //@_timer is only worked out when a line runs, so it does not change while waiting on input.
+    fn update_timer(&mut self) {
+        if let Some(end) = self.timer_end {
+            let left = end.checked_sub(self.clock.now()).unwrap_or_default();
+            let left = left.as_secs_f64().ceil();
+            self.variables
+                .insert(String::from("_timer"), Value::Num(left));
+        }
+    }
+
+    //plays the story through to its end, or until a breakpoint. Calling it again carries on
+    //from the breakpoint.
+    pub fn run(&mut self) -> Result<StepResult, StoryError> {
+        loop {
+            match self.step()? {
+                StepResult::Continue => {}
+                result => return Ok(result),
+            }
+        }
+    }
+
+    //stops the story in front of line, counted from 1 like the lines in errors.
+    pub fn set_breakpoint(&mut self, line: usize) {
+        self.breakpoints.insert(line);
+    }
+
+    pub fn clear_breakpoint(&mut self, line: usize) {
+        self.breakpoints.remove(&line);
+    }
+
+    //like run but gives up once the story has taken longer than limit by the clock.
+    pub fn run_with_timeout(&mut self, limit: Duration) -> Result<StepResult, StoryError> {
+        let start = self.clock.now();
+
+        loop {
+            if self.clock.now() - start > limit {
+                return Err(StoryError::Timeout {
+                    line: self.index + 1,
+                    limit,
+                });
+            }
+
+            match self.step()? {
+                StepResult::Continue => {}
+                result => return Ok(result),
+            }
+        }
+    }
+
+    //plays the story at path while reading it, one label at a time, so a huge story that only
+    //moves forward is never held in memory all at once. A story that can go back to a label it
+    //has passed, or uses *include or *restart, is loaded whole and run as usual instead.
+    //undo only reaches back to the last label, and line numbers in errors count from it.
+    pub fn run_streaming(&mut self, path: &Path) -> Result<(), Vec<StoryError>> {
+        let open = || match File::open(path) {
+            Ok(file) => Ok(BufReader::new(file)),
+            Err(why) => Err(vec![StoryError::Open {
+                path: path.display().to_string(),
+                reason: why.to_string(),
+            }]),
+        };
+
+        let declarations = match self.scan_linear(open()?) {
+            Some(declarations) => declarations,
+            None => {
+                self.processfile(open()?)?;
+                self.run().map_err(|e| vec![e])?;
+                return Ok(());
+            }
+        };
+
+        let errors = self.declare_defaults(declarations);
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        self.streaming = true;
+        let streamed = open().and_then(|reader| self.stream_parts(path, reader));
+        //however the parts ended, the lines held now are played the usual way.
+        self.streaming = false;
+        streamed?;
+
+        if let Some(label) = self.pending_label.take() {
+            return Err(vec![StoryError::UnknownLabel { label }]);
+        }
+
+        //lets the story finish the usual way, pausing if asked to.
+        self.step().map_err(|e| vec![e])?;
+        Ok(())
+    }
+
+    //reads and plays the story a label at a time until the reader runs out.
+    fn stream_parts(&mut self, path: &Path, reader: impl BufRead) -> Result<(), Vec<StoryError>> {
+        let mut reader = reader.lines();
+        //the label line that starts the next part, read while looking for the end of this one.
+        let mut next: Option<String> = None;
+
+        loop {
+            if self.index < self.lines.len() {
+                self.step().map_err(|e| vec![e])?;
+                continue;
+            }
+
+            let mut part: Vec<String> = next.take().into_iter().collect();
+
+            for line in &mut reader {
+                let line = line.map_err(|why| {
+                    vec![StoryError::Open {
+                        path: path.display().to_string(),
+                        reason: why.to_string(),
+                    }]
+                })?;
+
+                if !part.is_empty() && line.trim_start().starts_with(':') {
+                    next = Some(line);
+                    break;
+                }
+
+                part.push(line);
+            }
+
+            if part.is_empty() {
+                break;
+            }
+
+            //parts before the label a goto is heading for are skipped without being loaded.
+            if let Some(label) = &self.pending_label {
+                let (first, _) = self.strip_comment(unindent_command(part[0].clone()));
+
+                if !first.starts_with(':')
+                    || self.label_key(parse_checkpoint(&first[1..]).1) != self.label_key(label)
+                {
+                    continue;
+                }
+            }
+
+            self.pending_label = None;
+            self.lines.clear();
+            self.origins.clear();
+            self.labels.clear();
+            self.blocks.clear();
+            self.loop_counts.clear();
+            self.history.clear();
+            self.last_menu = None;
+            self.index = 0;
+            self.processfile(Cursor::new(part.join("\n")))?;
+        }
+
+        Ok(())
+    }
+
+    //reads through a story without keeping it, giving back the first assignment of each variable
+    //when every goto leads forward, or None when it has to be loaded whole to be played.
+    fn scan_linear(&mut self, reader: impl BufRead) -> Option<Vec<(String, String, usize)>> {
+        let mut seen = HashSet::new();
+        let mut declarations = Vec::new();
+        let mut depth = 0;
+
+        for (index, line) in reader.lines().enumerate() {
+            let (text, _) = self.strip_comment(unindent_command(line.ok()?));
+            let mut targets = Vec::new();
+
+            match text.chars().next() {
+                //a label inside a block would split the block across two parts.
+                Some(':') if depth > 0 => return None,
+                Some(':') => {
+                    seen.insert(self.label_key(parse_checkpoint(&text[1..]).1));
+                }
+                Some('#') => targets.push(text[1..].to_string()),
+                //< goes back to the line after the >label that called it.
+                Some('>' | '<') => return None,
+                Some('%') if is_include(&text) => return None,
+                //$load can go back to any line.
+                Some('$') => return None,
+                Some('?') => {
+                    if let Ok((_, right)) = self.tokenize(text.clone(), ":") {
+                        targets.push(right.replace("#", ""));
+                    }
+                }
+                Some('!') => {
+                    if let Ok((branches, other)) = self.iftokenize(text.clone(), ":") {
+                        let actions = branches.into_iter().map(|(_, then)| then).chain(other);
+                        targets
+                            .extend(actions.filter_map(|a| a.strip_prefix('#').map(String::from)));
+                    }
+                }
+                Some('*') => match &split_directive(&text).0[..] {
+                    //lines in a *preformatted block may start with : and split it across parts.
+                    "include" | "restart" | "preformatted" => return None,
+                    "branch" => targets.extend(
+                        split_directive(&text)
+                            .1
+                            .split_whitespace()
+                            .filter_map(|token| parse_branch(token).ok())
+                            .map(|(label, _, _)| label.to_string()),
+                    ),
+                    "table" => depth += 1,
+                    "endtable" => depth -= 1,
+                    _ => {}
+                },
+                Some('~') => match &block_keyword(&text)[..] {
+                    "while" | "if" | "repeat" => depth += 1,
+                    "endwhile" | "endif" | "endrepeat" => depth -= 1,
+                    _ => {}
+                },
+                Some('@') => {
+                    if let Some((l, r)) =
+                        split_assignment(&text).filter(|(l, _)| split_compound(l).is_none())
+                    {
+                        let (name, allowed) = parse_declaration(&l[1..]);
+
+                        if let Some(allowed) = allowed {
+                            self.enums.insert(name.clone(), allowed);
+                        }
+
+                        if list_call(&r, "split").is_some() {
+                            self.lists.entry(name).or_default();
+                        } else if !self.variables.contains_key(&name) {
+                            self.variables.insert(name.clone(), Value::Num(0.0));
+                            declarations.push((name, r, index));
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            for target in targets {
+                let target = target.trim();
+
+                //#menu and #@variable can lead anywhere, including back.
+                if target == "menu"
+                    || target.starts_with('@')
+                    || seen.contains(&self.label_key(target))
+                {
+                    return None;
+                }
+            }
+        }
+
+        Some(declarations)
+    }
+
+    //saves to save_path unless the last save was too recent.
+    fn checkpoint(&mut self) {
+        //lines held while streaming are only part of the story, so their index can not be saved.
+        if !self.streaming {
+            self.last_checkpoint = Some(self.capture());
+        }
+
+        let path = match &self.save_path {
+            Some(path) if !self.streaming => path.clone(),
+            _ => return,
+        };
+
+        let now = self.clock.now();
+
+        if matches!(self.last_save, Some(last) if now.saturating_sub(last) < self.save_interval) {
+            return;
+        }
+
+        match self.save_state(&path) {
+            Ok(()) => self.last_save = Some(now),
+            Err(why) => self.io.write_err(&format!(
+                "Warning: the checkpoint could not be saved to {}, {}.",
+                path.display(),
+                why
+            )),
+        }
+    }
+
+    //$save file and $load file, the file is found next to the story and may use @variables.
+    //a save or load that fails is only warned about so the story can carry on.
+    fn handle_state(&mut self, text: &str) -> Result<(), StoryError> {
+        let (keyword, args) = split_directive(text);
+        let path = self
+            .base_dir
+            .join(unquote(self.process_variables(args)?.trim()));
+        self.index += 1;
+
+        if keyword == "save" {
+            if let Err(why) = self.save_state(&path) {
+                let warning = format!("Warning: could not save to {}, {}.", path.display(), why);
+                self.io.write_err(&warning);
+            }
+        } else if let Err(e) = self.load_state(&path) {
+            self.io.write_err(&format!("Warning: {}", e));
+        }
+
+        Ok(())
+    }
+
+    //writes the line the story is on, its variables and the labels seen, read back by load_state.
+    pub fn save_state(&self, path: &Path) -> io::Result<()> {
+        let mut text = format!("index {}\n", self.index);
+
+        if let Some(label) = &self.last_label {
+            text.push_str(&format!("label {}\n", label));
+        }
+
+        for back in &self.call_stack {
+            text.push_str(&format!("return {}\n", back));
+        }
+
+        let mut visited: Vec<&String> = self.visited.iter().collect();
+        visited.sort();
+
+        for label in visited {
+            text.push_str(&format!("visited {}\n", label));
+        }
+
+        let mut variables: Vec<(&String, &Value)> = self.variables.iter().collect();
+        variables.sort_by(|a, b| a.0.cmp(b.0));
+
+        for (name, value) in variables {
+            text.push_str(&format!("@{} = {}\n", name, value));
+        }
+
+        let mut lists: Vec<(&String, &Vec<String>)> = self.lists.iter().collect();
+        lists.sort();
+
+        for (name, items) in lists {
+            text.push_str(&format!("list {}\n", name));
+
+            for item in items {
+                text.push_str(&format!("- {}\n", item));
+            }
+        }
+
+        //written next to the save then moved over it, so a crash while saving keeps the old one.
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        std::fs::write(&temp, text)?;
+        std::fs::rename(temp, path)
+    }
+
+    //picks the story up where a save left it, once the story itself has been loaded.
+    pub fn load_state(&mut self, path: &Path) -> Result<(), StoryError> {
+        let bad = |reason: String| StoryError::Save {
+            path: path.display().to_string(),
+            reason,
+        };
+        let text = std::fs::read_to_string(path).map_err(|why| bad(why.to_string()))?;
+        let mut index = None;
+        let mut label = None;
+        let mut call_stack = Vec::new();
+        let mut visited = HashSet::new();
+        let mut variables = HashMap::new();
+        let mut lists: HashMap<String, Vec<String>> = HashMap::new();
+        //the list the - items below belong to.
+        let mut list: Option<String> = None;
+
+        for (n, line) in text.lines().enumerate() {
+            if let Some(item) = line.strip_prefix("- ") {
+                match &list {
+                    Some(name) => lists
+                        .entry(name.clone())
+                        .or_default()
+                        .push(item.to_string()),
+                    None => {
+                        return Err(bad(format!("line {} is an item outside of a list", n + 1)))
+                    }
+                }
+
+                continue;
+            }
+
+            list = None;
+
+            if let Some(rest) = line.strip_prefix('@') {
+                match rest.split_once(" = ") {
+                    Some((name, value)) => {
+                        variables.insert(name.to_string(), Value::infer(value));
+                    }
+                    None => return Err(bad(format!("line {} has no value", n + 1))),
+                }
+
+                continue;
+            }
+
+            match line.split_once(' ') {
+                Some(("index", i)) => match usize::from_str(i) {
+                    Ok(i) if i < self.lines.len() => index = Some(i),
+                    _ => return Err(bad(format!("its index {} is not a line of this story", i))),
+                },
+                Some(("label", name)) => label = Some(name.to_string()),
+                Some(("return", i)) => match usize::from_str(i) {
+                    Ok(i) if i <= self.lines.len() => call_stack.push(i),
+                    _ => return Err(bad(format!("its return {} is not a line of this story", i))),
+                },
+                Some(("visited", name)) => {
+                    visited.insert(name.to_string());
+                }
+                Some(("list", name)) => {
+                    lists.insert(name.to_string(), Vec::new());
+                    list = Some(name.to_string());
+                }
+                _ if line.is_empty() => {}
+                _ => return Err(bad(format!("line {} is not understood", n + 1))),
+            }
+        }
+
+        //a save from before the story was changed may name labels it no longer has.
+        for name in label.iter().chain(visited.iter()) {
+            if self.label_index(name).is_none() {
+                return Err(bad(format!(
+                    "the label :{} is no longer in the story",
+                    name
+                )));
+            }
+        }
+
+        self.index =
+            index.ok_or_else(|| bad(String::from("it does not say where the story was")))?;
+        self.last_label = label;
+        self.call_stack = call_stack;
+        self.visited = visited;
+        self.variables.extend(variables);
+        self.lists.extend(lists);
+        Ok(())
+    }
+
+    //>label runs the lines after label until a < sends the story back to the line after the call.
+    fn handle_gosub(&mut self, text: &str) -> Result<(), StoryError> {
+        let label = unquote(self.process_variables(text)?.trim());
+        let target =
+            self.resolve_label(&label, self.index)
+                .ok_or_else(|| StoryError::UnknownLabel {
+                    label: label.clone(),
+                })?;
+
+        //a label that calls itself forever would otherwise grow the stack until memory runs out.
+        if self.call_stack.len() >= self.loop_limit {
+            return Err(StoryError::LoopLimit {
+                line: self.index + 1,
+                limit: self.loop_limit,
+            });
+        }
+
+        self.call_stack.push(self.index + 1);
+        self.index = target;
+        Ok(())
+    }
+
+    fn handle_return(&mut self) -> Result<(), StoryError> {
+        match self.call_stack.pop() {
+            Some(back) => self.index = back,
+            None => {
+                return Err(StoryError::ReturnWithoutGosub {
+                    line: self.index + 1,
+                })
+            }
+        }
+
+        Ok(())
+    }
+
+    //*branch cave forest*3 river?@hp>5 goes to one of the labels at random, forest three times as
+    //often as cave and river only while @hp>5. Use --seed to get the same branch every run.
+    fn process_branch(&mut self, args: &str) -> Result<(), StoryError> {
+        let mut choices = Vec::new();
+
+        for token in args.split_whitespace() {
+            let (label, weight, cond) =
+                parse_branch(token).map_err(|reason| StoryError::Malformed {
+                    line: self.index + 1,
+                    text: self.lines[self.index].clone(),
+                    reason,
+                })?;
+
+            if let Some(cond) = cond {
+                if !self.process_condition(cond)? {
+                    continue;
+                }
+            }
+
+            choices.push((label.to_string(), weight));
+        }
+
+        let total: f64 = choices.iter().map(|(_, weight)| weight).sum();
+
+        //every branch was ruled out, so the story carries on below.
+        if total <= 0.0 {
+            self.index += 1;
+            return Ok(());
+        }
+
+        let mut roll = self.rng.borrow_mut().gen_range(0.0..total);
+
+        for (label, weight) in &choices {
+            if roll < *weight {
+                return self.handle_goto(Some(label.clone()));
+            }
+
+            roll -= weight;
+        }
+
+        //only reached when rounding leaves a sliver past the last weight.
+        let (label, _) = choices.pop().unwrap();
+        self.handle_goto(Some(label))
+    }
+
+    //lines starting with * are comments unless they begin with a known directive.
+    fn handle_directive(&mut self, text: &str) -> Result<(), StoryError> {
+        let (keyword, args) = split_directive(text);
+
+        match &keyword[..] {
+            "color" => {
+                match args {
+                    "always" | "on" => self.color = ColorMode::Always,
+                    "never" | "off" => self.color = ColorMode::Never,
+                    _ => self.color = ColorMode::Auto,
+                }
+
+                self.index += 1;
+            }
+            "hud" => {
+                let hud = self.process_color_ifs(args)?;
+                let hud = self.process_variables(&hud)?;
+                let hud = self.render_markup(&hud);
+                self.emit(OutputEvent::Hud(hud));
+                self.index += 1;
+            }
+            "debug" => self.debug_console(),
+            "table" => self.process_table(args == "header")?,
+            "preformatted" => {
+                let end = self.blocks[&self.index];
+                let art = self.lines[self.index + 1..end].join("\n");
+                self.emit(OutputEvent::Narrative(art));
+                self.index = end + 1;
+            }
+            "restart" => self.restart(),
+            "branch" => self.process_branch(args)?,
+            //*stderr text is for notes that should not end up in a transcript of the story.
+            "stderr" => {
+                let text = self.process_variables(args)?;
+                self.io.write_err(&text);
+                self.index += 1;
+            }
+            "checkpoint" => {
+                self.index += 1;
+                self.checkpoint();
+            }
+            //*wait pauses like ~ but leaves the text on the screen, and may say what to press.
+            "wait" => {
+                if !self.advancing() {
+                    let text = match args {
+                        "" => String::from("Press Enter to Continue."),
+                        _ => self.process_variables(args)?,
+                    };
+
+                    self.prompt(&text);
+                    self.io.read_line();
+                }
+
+                self.index += 1;
+            }
+            "timer" => {
+                self.process_timer(args)?;
+                self.index += 1;
+            }
+            //*options [{}] {} changes how the options after it are numbered.
+            "options" => {
+                self.option_format = match args {
+                    "" => String::from("{}. {}"),
+                    _ => args.to_string(),
+                };
+                self.index += 1;
+            }
+            _ => self.index += 1,
+        }
+
+        Ok(())
+    }
+
+    //rows between *table and *endtable are split on | and lined up in columns.
+    fn process_table(&mut self, header: bool) -> Result<(), StoryError> {
+        let end = self.blocks[&self.index];
+        let mut rows: Vec<Vec<String>> = Vec::new();
+
+        for row in &self.lines[self.index + 1..end] {
+            if !row.trim().is_empty() {
+                let row = self.process_variables(row)?;
+                rows.push(row.split('|').map(|cell| cell.trim().to_string()).collect());
+            }
+        }
+
+        for line in render_table(&rows, header, self.wrap_width()) {
+            self.emit(OutputEvent::Narrative(line));
+        }
+
+        self.index = end + 1;
+        Ok(())
+    }
+
+    pub fn variable_schema(&self) -> Vec<VariableSchema> {
+        let mut schema: Vec<VariableSchema> = self
+            .variables
+            .keys()
+            .map(|name| VariableSchema {
+                name: name.clone(),
+                kind: self.enums.get(name).map(|_| String::from("enum")),
+                allowed: self.enums.get(name).cloned(),
+                default: self
+                    .defaults
+                    .get(name)
+                    .map(Value::to_string)
+                    .unwrap_or_else(|| String::from("0")),
+                description: self.descriptions.get(name).cloned(),
+            })
+            .chain(self.lists.keys().map(|name| VariableSchema {
+                name: name.clone(),
+                kind: Some(String::from("list")),
+                allowed: None,
+                default: String::new(),
+                description: self.descriptions.get(name).cloned(),
+            }))
+            .collect();
+
+        schema.sort_by(|a, b| a.name.cmp(&b.name));
+        schema
+    }
+
+    //every variable and list with its value and description, one per line sorted by name.
+    pub fn dump_variables(&self) -> String {
+        let mut lines: Vec<(String, String)> =
+            self.variables
+                .iter()
+                .map(|(name, value)| (name.clone(), format!("@{} = {}", name, value)))
+                .chain(self.lists.iter().map(|(name, list)| {
+                    (name.clone(), format!("@{} = [{}]", name, list.join(", ")))
+                }))
+                .collect();
+        lines.sort();
+
+        lines
+            .into_iter()
+            .map(|(name, line)| match self.descriptions.get(&name) {
+                Some(d) => format!("{}  // {}", line, d),
+                None => line,
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    //puts every variable and list back to how it was when the story was loaded.
+    fn reset_variables(&mut self) {
+        for (name, value) in self.variables.iter_mut() {
+            *value = self.defaults.get(name).cloned().unwrap_or(Value::Num(0.0));
+        }
+
+        for list in self.lists.values_mut() {
+            list.clear();
+        }
+    }
+
+    pub fn result(&self) -> StoryResult {
+        StoryResult {
+            score: self
+                .variables
+                .get("score")
+                .or_else(|| self.variables.get("_correct"))
+                .and_then(Value::as_number),
+            answers: self.answers.clone(),
+            ending: self.last_label.clone(),
+        }
+    }
+
+    //plays the story again from the top as if it was just loaded.
+    pub fn restart(&mut self) {
+        self.reset_variables();
+        self.visited.clear();
+        self.loop_counts.clear();
+        self.history.clear();
+        self.answers.clear();
+        self.call_stack.clear();
+        self.last_checkpoint = None;
+        self.last_label = None;
+        self.timer_end = None;
+        self.last_menu = None;
+        self.finished = false;
+        self.started = None;
+        self.index = 0;
+    }
+
+    fn debug_console(&mut self) {
+        if self.advancing() {
+            self.index += 1;
+            return;
+        }
+
+        loop {
+            self.prompt("debug> undo, vars or continue");
+
+            //with no input left the story just carries on.
+            match self.io.read_line().unwrap_or_default().trim() {
+                "undo" => {
+                    if self.undo() {
+                        return;
+                    }
+
+                    self.emit(OutputEvent::Prompt(String::from("Nothing to undo.")));
+                }
+                "vars" => {
+                    let mut names: Vec<&String> = self.variables.keys().collect();
+                    names.sort();
+
+                    let text = names
+                        .iter()
+                        .map(|n| format!("@{} = {}", n, self.variables[*n]))
+                        .collect::<Vec<String>>()
+                        .join("\n");
+                    self.emit(OutputEvent::Narrative(text));
+                }
+                _ => {
+                    self.index += 1;
+                    return;
+                }
+            }
+        }
+    }
+
+    fn snapshot(&mut self) {
+        if self.history.len() >= self.history_limit {
+            self.history.remove(0);
+        }
+
+        let snap = self.capture();
+        self.history.push(snap);
+    }
+
+    fn capture(&self) -> Snapshot {
+        Snapshot {
+            index: self.index,
+            variables: self.variables.clone(),
+            lists: self.lists.clone(),
+            visited: self.visited.clone(),
+            loop_counts: self.loop_counts.clone(),
+            answers: self.answers.clone(),
+            call_stack: self.call_stack.clone(),
+        }
+    }
+
+    fn restore(&mut self, snap: Snapshot) {
+        self.index = snap.index;
+        self.variables = snap.variables;
+        self.lists = snap.lists;
+        self.visited = snap.visited;
+        self.loop_counts = snap.loop_counts;
+        self.answers = snap.answers;
+        self.call_stack = snap.call_stack;
+    }
+
+    //goes back to just before the last question or input, returning false if there is none.
+    pub fn undo(&mut self) -> bool {
+        match self.history.pop() {
+            Some(snap) => {
+                self.restore(snap);
+                true
+            }
+            None => false,
+        }
+    }
+
+    //the player died, so the story goes back to the last checkpoint, or the top without one.
+    fn rewind(&mut self) {
+        if let Some(prompt) = self.death_prompt.clone() {
+            self.prompt(&prompt);
+            self.io.read_line();
+        }
+
+        match self.last_checkpoint.clone() {
+            Some(snap) => {
+                self.history.clear();
+                self.restore(snap);
+            }
+            None => self.restart(),
+        }
+    }
+
+    //nothing is shown while advancing to a label.
+    fn emit(&mut self, event: OutputEvent) {
+        if !self.advancing() {
+            self.io.emit(event);
+        }
+    }
+
+    fn prompt(&mut self, text: &str) {
+        self.emit(OutputEvent::Narrative(String::new()));
+        self.emit(OutputEvent::Prompt(text.to_string()));
+    }
+
+    //how long the story has been running.
+    fn elapsed(&self) -> Duration {
+        let now = self.clock.now();
+        now - self.started.unwrap_or(now)
+    }
+
+    fn input_wait(&mut self) {
+        if !self.advancing() {
+            self.prompt("Press Enter to Continue.");
+            self.io.read_line();
+        }
+
+        self.clear_screen();
+    }
+
+    fn clear_screen(&mut self) {
+        self.emit(OutputEvent::Clear);
+        self.index += 1;
+    }
+
+    fn printmove(&mut self, s: &str) -> Result<(), StoryError> {
+        let text = self.process_color_ifs(s)?;
+        let text = self.process_variables(&text)?;
+        let text = self.render_markup(&text);
+        let text = wrap_text(&text, self.wrap_width());
+        self.emit(OutputEvent::Narrative(text));
+        self.index += 1;
+        Ok(())
+    }
+
+    fn use_color(&self) -> bool {
+        match self.color {
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+            ColorMode::Auto => (self.color_support)(self.no_color),
+        }
+    }
+
+    //[color_if @hp<20 red]@hp[/color] becomes [color red]@hp[/color] while the condition holds,
+    //and just @hp when it does not. Done before variables are filled in so the condition sees names.
+    fn process_color_ifs(&mut self, text: &str) -> Result<String, StoryError> {
+        let mut out = String::new();
+        let mut rest = text.to_string();
+
+        while let Some(p) = rest.find("[color_if ") {
+            out.push_str(&rest[..p]);
+            let tag = &rest[p + "[color_if ".len()..];
+
+            let (cond, color, after) = match tag.find(']') {
+                Some(e) => match tag[..e].trim().rsplit_once(char::is_whitespace) {
+                    Some((cond, color)) => (
+                        cond.to_string(),
+                        color.to_string(),
+                        tag[e + 1..].to_string(),
+                    ),
+                    None => {
+                        out.push('[');
+                        rest = rest[p + 1..].to_string();
+                        continue;
+                    }
+                },
+                None => break,
+            };
+
+            if self.process_condition(cond.trim())? {
+                out.push_str(&format!("[color {}]", color));
+                rest = after;
+            } else {
+                //the [/color] that closed it goes too, so it does not end a color around it.
+                rest = after.replacen("[/color]", "", 1);
+            }
+        }
+
+        out.push_str(&rest);
+        Ok(out)
+    }
+
+    fn render_markup(&self, text: &str) -> String {
+        if self.color_markup {
+            colorize(text, self.use_color())
+        } else {
+            text.to_string()
+        }
+    }
+
+    //falls back to 80 columns when the width can not be found, such as when not on a tty.
+    fn wrap_width(&self) -> usize {
+        self.wrap_width
+            .or_else(|| (self.width_query)())
+            .unwrap_or(80)
+    }
+
+    fn handle_input(&mut self) -> Result<(), StoryError> {
+        self.snapshot();
+
+        if let Some(rest) = self.lines[self.index].strip_prefix("^l:") {
+            let rest = rest.to_string();
+            self.process_list_input(&rest);
+            return Ok(());
+        }
+
+        if let Some(rest) = self.lines[self.index].strip_prefix("^r:") {
+            let rest = rest.to_string();
+            return self.process_pattern_input(&rest);
+        }
+
+        let line = self.index + 1;
+        let text = self.lines[self.index].clone();
+        let malformed = |reason: String| StoryError::Malformed {
+            line,
+            text: text.clone(),
+            reason,
+        };
+        let (kind, left, name) = self.split_input(&text).map_err(malformed)?;
+        let mut ret;
+
+        if !self.variables.contains_key(&name) {
+            return Err(StoryError::MissingVariable { name, line });
+        }
+
+        let (bounds, default, prompt) = split_input_prompt(&left, kind).map_err(malformed)?;
+        let prompt = match default {
+            Some(default) => format!("{} [{}]", prompt, default),
+            None => prompt.to_string(),
+        };
+
+        match kind {
+            //while advancing the variable keeps its current value as the answer.
+            _ if self.advancing() => ret = self.variables[&name].to_string(),
+            'i' => {
+                loop {
+                    self.prompt(&prompt);
+
+                    ret = self.read_answer()?;
+
+                    //Enter on its own takes the default, which is checked like any other answer.
+                    if let (true, Some(default)) = (ret.trim().is_empty(), default) {
+                        ret = default.to_string();
+                    }
+
+                    if ret.chars().any(char::is_alphabetic) {
+                        self.emit(OutputEvent::Prompt(String::from(
+                            "You may only enter in a Number. Please try again.",
+                        )));
+                        continue;
+                    }
+
+                    match (bounds, f64::from_str(ret.trim())) {
+                        (Some((low, high)), Ok(n)) if n < low || n > high => {}
+                        (Some(_), Err(_)) => {}
+                        _ => break,
+                    }
+
+                    let (low, high) = bounds.unwrap();
+                    self.emit(OutputEvent::Prompt(format!(
+                        "Enter a number between {} and {}.",
+                        low, high
+                    )));
+                }
+            }
+            'y' => loop {
+                self.prompt(&prompt);
+                ret = self.read_answer()?;
+
+                if let (true, Some(default)) = (ret.trim().is_empty(), default) {
+                    ret = default.to_string();
+                }
+
+                if yes_no(&ret).is_some() {
+                    break;
+                }
+
+                self.emit(OutputEvent::Prompt(String::from(
+                    "Answer y or yes, or n or no.",
+                )));
+            },
+            _ => {
+                self.prompt(&prompt);
+                ret = self.read_answer()?;
+
+                if let (true, Some(default)) = (ret.trim().is_empty(), default) {
+                    ret = default.to_string();
+                }
+            }
+        }
+
+        //^s answers stay text even when they look like a number.
+        let value = match kind {
+            's' => Value::Str(ret),
+            //while advancing the answer is the value the variable already had.
+            'y' => match yes_no(&ret) {
+                Some(answer) => Value::Bool(answer),
+                None => Value::infer(&ret),
+            },
+            _ => Value::infer(&ret),
+        };
+        self.variables.insert(name, value);
+        self.index += 1;
+        Ok(())
+    }
+
+    //^i prompt:@name split into its type, i or s, what comes after the type, and the variable.
+    fn split_input(&self, text: &str) -> Result<(char, String, String), String> {
+        let (left, right) = self
+            .tokenize(text.to_string(), ":")
+            .map_err(|_| String::from("input should look like ^i prompt:@variable"))?;
+        let kind = match left[1..].chars().next() {
+            Some(kind @ ('i' | 's' | 'y')) => kind,
+            Some(kind) => {
+                return Err(format!(
+                "^{} is not a type of input, use ^i for a number, ^s for text or ^y for yes or no",
+                kind
+            ))
+            }
+            None => return Err(String::from(
+                "input is missing its type, use ^i for a number, ^s for text or ^y for yes or no",
+            )),
+        };
+
+        match right.strip_prefix('@') {
+            Some(name) if !name.is_empty() => Ok((kind, left[2..].to_string(), name.to_string())),
+            _ => Err(String::from("input must be stored in a @variable")),
+        }
+    }
+
+    //an answer to the line being run, which can not be given once input has ended.
+    fn read_answer(&mut self) -> Result<String, StoryError> {
+        let line = self.index + 1;
+        let ret = self
+            .io
+            .read_line()
+            .ok_or(StoryError::UnexpectedEof { line })?;
+
+        match self.trim_input {
+            true => Ok(ret.trim().to_string()),
+            false => Ok(ret),
+        }
+    }
+
+    //asks again until the answer matches the pattern given between the slashes.
+    fn process_pattern_input(&mut self, rest: &str) -> Result<(), StoryError> {
+        let (name, pattern, prompt) =
+            split_pattern_input(rest).ok_or_else(|| StoryError::Malformed {
+                line: self.index + 1,
+                text: self.lines[self.index].clone(),
+                reason: String::from("input should look like ^r:variable /pattern/ prompt"),
+            })?;
+        let re = self.regexes[pattern].clone();
+        let mut ret = self.variables[name].to_string();
+
+        if !self.advancing() {
+            loop {
+                self.prompt(prompt);
+                ret = self.read_answer()?;
+
+                if re.is_match(&ret) {
+                    break;
+                }
+
+                self.emit(OutputEvent::Prompt(format!(
+                    "That answer is not in the right form, it must match /{}/. Please try again.",
+                    pattern
+                )));
+            }
+        }
+
+        self.variables.insert(name.to_string(), Value::Str(ret));
+        self.index += 1;
+        Ok(())
+    }
+
+    //keeps asking until a blank line or the end of input, one list item per answer.
+    fn process_list_input(&mut self, rest: &str) {
+        let (name, prompt) = split_list_input(rest);
+        let mut items = Vec::new();
+
+        if !self.advancing() {
+            loop {
+                self.prompt(prompt);
+                let ret = match self.read_answer() {
+                    Ok(ret) if !ret.is_empty() => ret,
+                    _ => break,
+                };
+
+                items.push(ret);
+            }
+        }
+
+        self.lists.insert(name.to_string(), items);
+        self.index += 1;
+    }
+
+    fn handle_question(&mut self) -> Result<(), StoryError> {
+        self.snapshot();
+        self.last_menu = Some(self.index);
+        let mut gotos: Vec<String> = Vec::new();
+        let mut hotkeys: Vec<Option<char>> = Vec::new();
+        let mut texts: Vec<String> = Vec::new();
+        let mut correct: Vec<bool> = Vec::new();
+        let start = self.index;
+        let mut q = 0;
+
+        while self.index < self.lines.len() && self.lines[self.index].starts_with('?') {
+            let text = self.lines[self.index].clone();
+            let (left, right) =
+                self.tokenize(text.clone(), ":")
+                    .map_err(|_| StoryError::Malformed {
+                        line: self.index + 1,
+                        text,
+                        reason: String::from("a question option needs one : before its #label"),
+                    })?;
+            let (is_correct, option) = parse_correct(&left[1..]);
+            let (hotkey, text) = parse_hotkey(option);
+            gotos.push(right.replace("#", ""));
+            hotkeys.push(hotkey);
+            texts.push(text.to_string());
+            correct.push(is_correct);
+
+            let display = match hotkey {
+                Some(key) => format!("{}) {}", key, text),
+                None => format_option(&self.option_format, q + 1, text),
+            };
+
+            self.emit(OutputEvent::MenuOption {
+                n: q + 1,
+                hotkey,
+                text: text.to_string(),
+                display,
+            });
+
+            q += 1;
+            self.index += 1;
+        }
+
+        let keys: Vec<String> = hotkeys.iter().flatten().map(|k| k.to_string()).collect();
+        let ask = match keys.is_empty() {
+            true => format!("Enter a number from 1 to {}", q),
+            false => format!("Enter a number from 1 to {} or {}", q, keys.join(", ")),
+        };
+
+        //while advancing the first option is always taken.
+        let input = loop {
+            if self.advancing() {
+                break 1;
+            }
+
+            self.emit(OutputEvent::Prompt(ask.clone()));
+            let ret = self
+                .io
+                .read_line()
+                .ok_or(StoryError::UnexpectedEof { line: start + 1 })?;
+            let ret = ret.trim();
+            let mut chars = ret.chars();
+
+            if let (Some(c), None) = (chars.next(), chars.next()) {
+                let c = c.to_ascii_lowercase();
+
+                if let Some(p) = hotkeys.iter().position(|k| *k == Some(c)) {
+                    break p + 1;
+                }
+            }
+
+            //the whole answer is read as one number first, so 10 is never taken as 1.
+            match usize::from_str(ret) {
+                Ok(i) if (1..=q).contains(&i) => break i,
+                Ok(i) => self.emit(OutputEvent::Prompt(format!("There is no option {}", i))),
+                Err(_) => self.emit(OutputEvent::Prompt(String::from("You must use a number"))),
+            }
+        };
+
+        //only questions with an option marked ?* are marked.
+        let marked = match correct.contains(&true) {
+            true => Some(correct[input - 1]),
+            false => None,
+        };
+
+        if let Some(right) = marked {
+            let name = if right { "_correct" } else { "_incorrect" };
+            let count = self.variables.entry(name.to_string()).or_default();
+            *count = Value::Num(count.as_number().unwrap_or(0.0) + 1.0);
+        }
+
+        self.answers.push(Answer {
+            line: start + 1,
+            option: input,
+            text: texts[input - 1].clone(),
+            correct: marked,
+        });
+
+        let label = unquote(self.process_variables(&gotos[input - 1])?.trim());
+        match self.resolve_label(&label, start) {
+            Some(v) => self.index = v,
+            None if self.streaming => {
+                self.pending_label = Some(label);
+                self.index = self.lines.len();
+            }
+            None => {
+                let line = start + input - 1;
+
+                return Err(StoryError::Malformed {
+                    line: line + 1,
+                    text: self.lines[line].clone(),
+                    reason: format!("there is no label :{}", label),
+                });
+            }
+        };
+
+        Ok(())
+    }
+
+    fn handle_assign(&mut self, opt: Option<String>) -> Result<(), StoryError> {
+        let text = match &opt {
+            None => self.lines[self.index].clone(),
+            Some(s) => s.clone(),
+        };
+
+        match split_assignment(&text) {
+            Some((l, r)) if split_compound(&l).is_some() => {
+                let (name, op) = split_compound(&l).unwrap();
+                let value = self.compound_value(name, op, &r)?;
+                self.variables.insert(name.to_string(), value);
+                self.index += 1;
+            }
+            Some((l, r)) => {
+                let (name, _) = parse_declaration(&l[1..]);
+
+                if let Some(args) = list_call(&r, "split") {
+                    let items = self.split_list(args)?;
+                    self.lists.insert(name, items);
+                    self.index += 1;
+                    return Ok(());
+                }
+
+                let value = self.evaluate(&name, &r)?;
+
+                match self.variables.get_mut(&name) {
+                    Some(variable) => *variable = Value::infer(&value),
+                    None => {
+                        return Err(StoryError::MissingVariable {
+                            name,
+                            line: self.index + 1,
+                        })
+                    }
+                }
+
+                self.index += 1;
+            }
+            None => match &opt {
+                None => match split_dialogue(&text) {
+                    Some((speaker, said)) => self.process_dialogue(speaker, said)?,
+                    None => self.printmove(&self.lines[self.index].clone())?,
+                },
+                Some(action) => {
+                    return Err(StoryError::Malformed {
+                        line: self.index + 1,
+                        text: self.lines[self.index].clone(),
+                        reason: format!("{} should assign a value, like @name = value", action),
+                    })
+                }
+            },
+        };
+
+        Ok(())
+    }
+
+    //@gold += 5 works out @gold + (5), and += on text adds the new text to its end.
+    fn compound_value(&mut self, name: &str, op: char, r: &str) -> Result<Value, StoryError> {
+        //@name ?= "Hero" only fills in a variable that is still empty, like a skipped input.
+        if op == '?' {
+            return match self.variables.get(name) {
+                Some(Value::Str(text)) if unquote(text).is_empty() => {
+                    Ok(Value::infer(&self.evaluate(name, r)?))
+                }
+                None => Ok(Value::infer(&self.evaluate(name, r)?)),
+                Some(current) => Ok(current.clone()),
+            };
+        }
+
+        let current = match self.variables.get(name) {
+            Some(current) => current.clone(),
+            None => {
+                return Err(StoryError::MissingVariable {
+                    name: name.to_string(),
+                    line: self.index + 1,
+                })
+            }
+        };
+
+        match (&current, op) {
+            (Value::Num(_), _) => {
+                let value = self.evaluate(name, &format!("@{} {} ({})", name, op, r))?;
+
+                match Value::infer(&value) {
+                    Value::Num(n) => Ok(Value::Num(n)),
+                    _ => Err(StoryError::TypeMismatch {
+                        left: current.to_string(),
+                        right: r.trim().to_string(),
+                        kinds: (current.kind(), "text"),
+                        op: format!("{}=", op),
+                        line: self.index + 1,
+                    }),
+                }
+            }
+            (Value::Str(text), '+') => {
+                let added = unquote(self.evaluate_value(r)?.trim());
+
+                //"Bob" += " Jr" keeps the quotes around the whole of "Bob Jr"
+                Ok(Value::Str(match text.starts_with('"') {
+                    true => format!("\"{}{}\"", unquote(text), added),
+                    false => format!("{}{}", text, added),
+                }))
+            }
+            _ => Err(StoryError::TypeMismatch {
+                left: current.to_string(),
+                right: r.trim().to_string(),
+                kinds: (current.kind(), Value::infer(r).kind()),
+                op: format!("{}=", op),
+                line: self.index + 1,
+            }),
+        }
+    }
+
+    //@Alice: Hello there shows the speaker in bold and keeps them in @_speaker
+    fn process_dialogue(&mut self, speaker: &str, said: &str) -> Result<(), StoryError> {
+        self.variables
+            .insert(String::from("_speaker"), Value::Str(speaker.to_string()));
+        self.printmove(&format!("[color bold]{}:[/color] {}", speaker, said))
+    }
+
+    //works out the value the right side of an assignment to name gives.
+    fn evaluate(&mut self, name: &str, r: &str) -> Result<String, StoryError> {
+        let value = self.evaluate_value(r)?;
+
+        match self.enums.get(name) {
+            Some(allowed) => {
+                let value = value.trim().to_string();
+
+                if !allowed.contains(&value) {
+                    return Err(StoryError::InvalidEnumValue {
+                        name: name.to_string(),
+                        value,
+                        allowed: allowed.clone(),
+                        line: self.index + 1,
+                    });
+                }
+
+                Ok(value)
+            }
+            None => Ok(value),
+        }
+    }
+
+    //works out @gold * 2 + 1 against the variables as they are now, without running any line.
+    //rand and chance roll on a copy of the story's random numbers, so the story's own rolls
+    //come out the same whether or not anything was worked out in between.
+    pub fn eval_expression(&self, expression: &str) -> Result<Value, StoryError> {
+        let rng = self.rng.borrow().clone();
+        let value = self.evaluate_value(expression);
+        self.rng.replace(rng);
+        Ok(Value::infer(value?.trim()))
+    }
+
+    fn evaluate_value(&self, r: &str) -> Result<String, StoryError> {
+        //cond ? a : b picks a side first, and either side may hold another one.
+        if let Some((cond, then, other)) = split_ternary(r) {
+            let branch = if self.process_condition(cond)? {
+                then
+            } else {
+                other
+            };
+
+            return Ok(unquote(self.evaluate_value(branch)?.trim()));
+        }
+
+        let p = self.process_functions(r)?;
+
+        Ok(match self.expressions.eval(&p, &self.variables) {
+            //update as variable
+            Ok(v) => v.to_string(),
+            //no calulations done becuase its a string so process as string.
+            Err(_) => p,
+        })
+    }
+
+    //split(text, separator, quote) where quote defaults to " and an empty one turns quoting off.
+    fn split_list(&self, args: Vec<String>) -> Result<Vec<String>, StoryError> {
+        let args = args
+            .iter()
+            .map(|arg| self.argument(arg))
+            .collect::<Result<Vec<String>, StoryError>>()?;
+
+        let error = |message: String| StoryError::Function {
+            name: String::from("split"),
+            message,
+            line: self.index + 1,
+        };
+
+        if args.len() < 2 || args.len() > 3 {
+            return Err(error(format!(
+                "takes 2 or 3 arguments but got {}",
+                args.len()
+            )));
+        }
+
+        if args[1].is_empty() {
+            return Err(error(String::from("needs a separator that is not empty")));
+        }
+
+        let quote = match args.get(2) {
+            Some(q) => q.chars().next(),
+            None => Some('"'),
+        };
+
+        Ok(split_fields(&args[0], &args[1], quote))
+    }
+
+    fn handle_goto(&mut self, opt: Option<String>) -> Result<(), StoryError> {
+        let text = match opt {
+            None => self.lines[self.index].clone(),
+            Some(s) => s,
+        };
+
+        //#@next goes to the label held in @next
+        let label = unquote(
+            self.process_variables(&text.replace("#", "").replace(":", ""))?
+                .trim(),
+        );
+        match (self.resolve_label(&label, self.index), self.last_menu) {
+            (Some(v), _) => self.index = v,
+            //#menu goes back to the last question block unless the story has its own :menu
+            (None, Some(menu)) if label == "menu" => self.index = menu,
+            //the label is further on in a story that has not been read that far yet
+            _ if self.streaming => {
+                self.pending_label = Some(label);
+                self.index = self.lines.len();
+            }
+            _ => {
+                return Err(StoryError::Malformed {
+                    line: self.index + 1,
+                    text: self.lines[self.index].clone(),
+                    reason: format!("there is no label :{}", label),
+                })
+            }
+        };
+
+        Ok(())
+    }
+
+    fn handle_if(&mut self) -> Result<(), StoryError> {
+        let line = self.index + 1;
+        let text = self.lines[self.index].clone();
+        let malformed = |reason: &str| StoryError::Malformed {
+            line,
+            text: text.clone(),
+            reason: reason.to_string(),
+        };
+        let (branches, other) = self
+            .iftokenize(text.clone(), ":")
+            .map_err(|_| malformed("an if needs a condition, a : and an action"))?;
+        let mut taken = None;
+
+        //the first condition that holds picks the action, the rest are not looked at.
+        for (cond, then) in branches {
+            if self.process_condition(&cond)? {
+                taken = Some(then);
+                break;
+            }
+        }
+
+        let action = match taken.or(other) {
+            Some(action) => action,
+            None => {
+                self.index += 1;
+                return Ok(());
+            }
+        };
+        let cond = action.as_str();
+
+        match cond.chars().next() {
+            Some('#') => self.handle_goto(Some(cond.to_string())),
+            Some('@') => self.handle_assign(Some(cond.to_string())),
+            Some('"') => {
+                let s = cond[1..cond.len()].to_string();
+
+                if !s.ends_with('"') {
+                    return Err(malformed(
+                        "text to print that starts with \" must end with \"",
+                    ));
+                }
+
+                self.printmove(&s.trim_end_matches('"').to_string())
+            }
+            _ => self.printmove(&cond.to_string()),
+        }
+    }
+}
+
+//every file in a .zip, .tar.gz or .tgz by its path inside the archive.
+fn read_archive(path: &Path) -> io::Result<HashMap<PathBuf, Vec<u8>>> {
+    let file = File::open(path)?;
+    let name = path.to_string_lossy().to_lowercase();
+    let mut files = HashMap::new();
+
+    if name.ends_with(".zip") {
+        let mut zip = zip::ZipArchive::new(file)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        for i in 0..zip.len() {
+            let mut entry = zip
+                .by_index(i)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+            if entry.is_file() {
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                files.insert(PathBuf::from(entry.name()), data);
+            }
+        }
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(file));
+
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+
+            if entry.header().entry_type().is_file() {
+                let path = entry.path()?.to_path_buf();
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                files.insert(path, data);
+            }
+        }
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "only .zip, .tar.gz and .tgz archives can be read",
+        ));
+    }
+
+    Ok(files)
+}
+
+//pads every cell to its column's widest cell, shrinking the widest columns to fit width.
+fn render_table(rows: &[Vec<String>], header: bool, width: usize) -> Vec<String> {
+    let columns = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+    let mut widths = vec![0; columns];
+
+    for row in rows {
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = widths[i].max(cell.chars().count());
+        }
+    }
+
+    let spacing = columns.saturating_sub(1) * 3;
+
+    while width > 0 && widths.iter().sum::<usize>() + spacing > width {
+        let widest = (0..columns).max_by_key(|&i| widths[i]).unwrap();
+
+        if widths[widest] <= 1 {
+            break;
+        }
+
+        widths[widest] -= 1;
+    }
+
+    let mut lines = Vec::new();
+
+    for (r, row) in rows.iter().enumerate() {
+        let cells: Vec<String> = (0..columns)
+            .map(|i| {
+                let cell = row.get(i).map(|c| &c[..]).unwrap_or("");
+
+                if cell.chars().count() > widths[i] {
+                    let cut: String = cell.chars().take(widths[i] - 1).collect();
+                    format!("{}…", cut)
+                } else {
+                    format!("{:<w$}", cell, w = widths[i])
+                }
+            })
+            .collect();
+
+        lines.push(cells.join(" | ").trim_end().to_string());
+
+        if header && r == 0 {
+            let rule: Vec<String> = widths.iter().map(|w| "-".repeat(*w)).collect();
+            lines.push(rule.join("-+-"));
+        }
+    }
+
+    lines
+}
+
+fn format_option(format: &str, n: usize, text: &str) -> String {
+    format
+        .replacen("{}", &n.to_string(), 1)
+        .replacen("{}", text, 1)
+}
+
+//pads value to a spec of [[fill]align][width], where align is < left, > right or ^ center.
+fn format_spec(value: &str, spec: &str) -> String {
+    let chars: Vec<char> = spec.chars().collect();
+    let is_align = |c: &char| matches!(c, '<' | '>' | '^');
+    let (fill, align, width) = match (chars.first(), chars.get(1)) {
+        (Some(f), Some(a)) if is_align(a) => (*f, *a, &chars[2..]),
+        (Some(a), _) if is_align(a) => (' ', *a, &chars[1..]),
+        _ => (' ', '<', &chars[..]),
+    };
+    let width = usize::from_str(&width.iter().collect::<String>()).unwrap_or(0);
+    let pad = width.saturating_sub(value.chars().count());
+    let fill = |n: usize| fill.to_string().repeat(n);
+
+    match align {
+        '>' => format!("{}{}", fill(pad), value),
+        '^' => format!("{}{}{}", fill(pad / 2), value, fill(pad - pad / 2)),
+        _ => format!("{}{}", value, fill(pad)),
+    }
+}
+
+fn terminal_color(no_color: bool) -> bool {
+    use std::io::IsTerminal;
+
+    !no_color && io::stdout().is_terminal()
+}
+
+fn color_code(name: &str) -> Option<&'static str> {
+    match name {
+        "black" => Some("30"),
+        "red" => Some("31"),
+        "green" => Some("32"),
+        "yellow" => Some("33"),
+        "blue" => Some("34"),
+        "magenta" => Some("35"),
+        "cyan" => Some("36"),
+        "white" => Some("37"),
+        "bold" => Some("1"),
+        _ => None,
+    }
+}
+
+//turns [color name] and [/color] into terminal codes, or drops them when color is off.
+fn colorize(text: &str, enabled: bool) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+
+    while let Some(p) = rest.find('[') {
+        out.push_str(&rest[..p]);
+        rest = &rest[p..];
+
+        if let Some(after) = rest.strip_prefix("[/color]") {
+            if enabled {
+                out.push_str("\x1b[0m");
+            }
+
+            rest = after;
+            continue;
+        }
+
+        let tag = rest
+            .strip_prefix("[color ")
+            .and_then(|r| r.find(']').map(|e| (&r[..e], &r[e + 1..])));
+
+        match tag.and_then(|(name, after)| color_code(name.trim()).map(|c| (c, after))) {
+            Some((code, after)) => {
+                if enabled {
+                    out.push_str(&format!("\x1b[{}m", code));
+                }
+
+                rest = after;
+            }
+            None => {
+                out.push('[');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+//the width text takes up on screen, not counting color codes.
+fn visible_len(text: &str) -> usize {
+    let mut len = 0;
+    let mut escape = false;
+
+    for c in text.chars() {
+        match c {
+            '\x1b' => escape = true,
+            'm' if escape => escape = false,
+            _ if escape => {}
+            _ => len += 1,
+        }
+    }
+
+    len
+}
+
+fn terminal_width() -> Option<usize> {
+    term_size::dimensions_stdout().map(|(w, _)| w)
+}
+
+//breaks lines longer than width on whitespace, lines that already fit are left alone.
+fn wrap_text(text: &str, width: usize) -> String {
+    if width == 0 {
+        return text.to_string();
+    }
+
+    let mut out: Vec<String> = Vec::new();
+
+    for line in text.split('\n') {
+        if visible_len(line) <= width {
+            out.push(line.to_string());
+            continue;
+        }
+
+        let mut current = String::new();
+
+        for word in line.split_whitespace() {
+            if !current.is_empty() && visible_len(&current) + 1 + visible_len(word) > width {
+                out.push(current);
+                current = String::new();
+            }
+
+            if !current.is_empty() {
+                current.push(' ');
+            }
+
+            current.push_str(word);
+        }
+
+        out.push(current);
+    }
+
+    out.join("\n")
+}
+
+//orders name after the variables its starting value uses, stopping on a cycle.
+fn default_order(
+    name: &str,
+    sources: &HashMap<String, (String, usize)>,
+    done: &mut HashSet<String>,
+    stack: &mut Vec<String>,
+    order: &mut Vec<String>,
+) -> Result<(), StoryError> {
+    if done.contains(name) {
+        return Ok(());
+    }
+
+    if let Some(p) = stack.iter().position(|n| n == name) {
+        let mut names = stack[p..].to_vec();
+        names.push(name.to_string());
+
+        return Err(StoryError::CyclicDefault {
+            names,
+            line: sources[name].1 + 1,
+        });
+    }
+
+    stack.push(name.to_string());
+
+    for dep in parse_variables(&sources[name].0) {
+        if sources.contains_key(&dep) {
+            default_order(&dep, sources, done, stack, order)?;
+        }
+    }
+
+    stack.pop();
+    done.insert(name.to_string());
+    order.push(name.to_string());
+    Ok(())
+}
+
+//splits @name = value on the first =, so the value may hold = of its own. @a==b is not one.
+fn split_assignment(line: &str) -> Option<(String, String)> {
+    //@Teacher: 2 + 2 = 4 is someone talking, the = is part of what they said.
+    if split_dialogue(line).is_some() {
+        return None;
+    }
+
+    let p = line.find('=')?;
+
+    if line[p + 1..].starts_with('=') {
+        return None;
+    }
+
+    Some((
+        line[..p].trim().to_string(),
+        line[p + 1..].trim().to_string(),
+    ))
+}
+
+//@Name: text is a line of dialogue, giving the speaker and what they said. The name comes
+//before any =, and @mood:enum(calm,angry) = calm with its type right after the : is a declaration.
+fn split_dialogue(line: &str) -> Option<(&str, &str)> {
+    let rest = line.strip_prefix('@')?;
+    let p = rest.find(':')?;
+    let speaker = rest[..p].trim();
+
+    if speaker.is_empty()
+        || !speaker
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == ' ' || c == '\'')
+    {
+        return None;
+    }
+
+    let said = &rest[p + 1..];
+
+    if !said.starts_with(char::is_whitespace) && said.contains('=') {
+        return None;
+    }
+
+    Some((speaker, said.trim()))
+}
+
+//splits the left side of an assignment into the variable name and any enum(a,b,c) values.
+fn parse_declaration(left: &str) -> (String, Option<Vec<String>>) {
+    let left = left.trim();
+
+    match left.find(':') {
+        Some(p) => {
+            let kind = left[p + 1..].trim();
+            let allowed = kind
+                .strip_prefix("enum(")
+                .and_then(|k| k.strip_suffix(')'))
+                .map(|k| k.split(',').map(|v| v.trim().to_string()).collect());
+
+            (left[..p].trim().to_string(), allowed)
+        }
+        None => (left.to_string(), None),
+    }
+}
+
+//a hotkey like [2] is picked before the number, so it hides whichever option is second.
+fn hotkey_number_conflicts(hotkeys: &HashMap<char, usize>, options: &[usize]) -> Vec<StoryError> {
+    let mut errors = Vec::new();
+
+    for (key, line) in hotkeys {
+        let n = match key.to_digit(10) {
+            Some(n) if n >= 1 => n as usize,
+            _ => continue,
+        };
+
+        match options.get(n - 1) {
+            Some(option) if option != line => errors.push(StoryError::DuplicateHotkey {
+                key: *key,
+                first_line: option + 1,
+                line: line + 1,
+            }),
+            _ => {}
+        }
+    }
+
+    errors.sort_by_key(|e| match e {
+        StoryError::DuplicateHotkey { line, .. } => *line,
+        _ => 0,
+    });
+    errors
+}
+
+//label*weight?condition from a *branch line, the weight is 1 when not given.
+fn parse_branch(token: &str) -> Result<(&str, f64, Option<&str>), String> {
+    let (rest, cond) = match token.split_once('?') {
+        Some((rest, cond)) => (rest, Some(cond)),
+        None => (token, None),
+    };
+
+    let (label, weight) = match rest.split_once('*') {
+        Some((label, weight)) => match f64::from_str(weight) {
+            Ok(w) if w >= 0.0 => (label, w),
+            _ => {
+                return Err(format!(
+                    "the weight of {} is not a number of 0 or more",
+                    label
+                ))
+            }
+        },
+        None => (rest, 1.0),
+    };
+
+    if label.is_empty() {
+        return Err(format!("{} has no label", token));
+    }
+
+    Ok((label, weight, cond))
+}
+
+//:*shop is a checkpoint, the story is saved whenever it is reached.
+fn parse_checkpoint(text: &str) -> (bool, &str) {
+    match text.strip_prefix('*') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    }
+}
+
+//?*Paris:#right marks the option as the right answer.
+fn parse_correct(text: &str) -> (bool, &str) {
+    match text.strip_prefix('*') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    }
+}
+
+//takes a leading [k] off a question option, returning the lowercase hotkey and the option text.
+fn parse_hotkey(text: &str) -> (Option<char>, &str) {
+    let mut chars = text.chars();
+
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('['), Some(key), Some(']')) if !key.is_whitespace() => {
+            let rest = &text[2 + key.len_utf8()..];
+            (Some(key.to_ascii_lowercase()), rest)
+        }
+        _ => (None, text),
+    }
+}
+
+//splits the part after ^l: into the list name and its prompt.
+fn split_list_input(rest: &str) -> (&str, &str) {
+    let rest = rest.trim_start();
+    let (name, prompt) = rest.split_at(rest.find(char::is_whitespace).unwrap_or(rest.len()));
+    (name.trim_start_matches('@'), prompt.trim())
+}
+
+//splits the part after ^r: into the variable name, the pattern between / and /, and the prompt.
+fn split_pattern_input(rest: &str) -> Option<(&str, &str, &str)> {
+    let (name, rest) = split_list_input(rest);
+    let rest = rest.strip_prefix('/')?;
+    let mut escaped = false;
+
+    for (i, c) in rest.char_indices() {
+        match c {
+            '\\' => escaped = !escaped,
+            '/' if !escaped => return Some((name, &rest[..i], rest[i + 1..].trim())),
+            _ => escaped = false,
+        }
+    }
+
+    None
+}
+
+//^s(Hero) Your name? gives the bounds of an ^i, the answer taken when Enter is pressed on its
+//own, and the prompt.
+fn split_input_prompt(text: &str, kind: char) -> Result<(Bounds, Option<&str>, &str), String> {
+    let numeric = kind == 'i';
+    let (bounds, rest) = match numeric {
+        true => parse_bounds(text)?,
+        false => (None, text),
+    };
+    let (default, prompt) = match rest.strip_prefix('(') {
+        Some(rest) => match rest.split_once(')') {
+            Some((default, prompt)) => (Some(default), prompt.trim_start()),
+            None => return Err(String::from("the default answer needs a closing )")),
+        },
+        None => (None, rest),
+    };
+
+    //a default the player could not type themselves would be asked for forever.
+    if let (true, Some(default)) = (numeric, default) {
+        match (f64::from_str(default.trim()), bounds) {
+            (Ok(n), Some((low, high))) if n < low || n > high => {
+                return Err(format!("the default {} is not from {} to {}", n, low, high))
+            }
+            (Ok(_), _) => {}
+            (Err(_), _) => return Err(format!("the default {} is not a number", default)),
+        }
+    }
+
+    if let ('y', Some(default)) = (kind, default) {
+        if yes_no(default).is_none() {
+            return Err(format!("the default {} is not yes or no", default));
+        }
+    }
+
+    Ok((bounds, default, prompt))
+}
+
+//^y takes y or yes as true and n or no as false, in any case, and nothing else.
+fn yes_no(answer: &str) -> Option<bool> {
+    match &answer.trim().to_lowercase()[..] {
+        "y" | "yes" => Some(true),
+        "n" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+//^i[1..10] Enter a number: gives the lowest and highest answers allowed and the prompt after them.
+fn parse_bounds(prompt: &str) -> Result<(Bounds, &str), String> {
+    let rest = match prompt.strip_prefix('[') {
+        Some(rest) => rest,
+        None => return Ok((None, prompt)),
+    };
+    let (range, prompt) = rest
+        .split_once(']')
+        .ok_or_else(|| String::from("the bounds need a closing ]"))?;
+    let (low, high) = range
+        .split_once("..")
+        .ok_or_else(|| String::from("the bounds should look like [1..10]"))?;
+
+    match (f64::from_str(low.trim()), f64::from_str(high.trim())) {
+        (Ok(low), Ok(high)) if low <= high => Ok((Some((low, high)), prompt.trim_start())),
+        (Ok(low), Ok(high)) => Err(format!(
+            "the lowest answer {} is more than the highest {}",
+            low, high
+        )),
+        _ => Err(format!("{} is not two numbers", range)),
+    }
+}
+
+const FUNCTIONS: &[&str] = &[
+    "visited", "elapsed", "tobase", "frombase", "rand", "randf", "chance", "replace", "trim",
+    "squeeze", "len", "upper", "lower", "substr", "approx",
+];
+
+//finds the first call to one of the built in functions, returning where it starts and its name.
+fn find_function(text: &str) -> Option<(usize, &'static str)> {
+    let mut found: Option<(usize, &'static str)> = None;
+
+    for name in FUNCTIONS {
+        let mut from = 0;
+
+        while let Some(p) = text[from..].find(&format!("{}(", name)) {
+            let start = from + p;
+            let prev = text[..start].chars().last();
+
+            //@len( is a variable called len, not a call.
+            if !matches!(prev, Some(c) if c.is_alphanumeric() || c == '_' || c == '@') {
+                match found {
+                    Some((f, _)) if f <= start => {}
+                    _ => found = Some((start, name)),
+                }
+
+                break;
+            }
+
+            from = start + 1;
+        }
+    }
+
+    found
+}
+
+//text must start with ( and the index of its matching ) is returned.
+fn matching_paren(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut quoted = false;
+
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => {
+                depth -= 1;
+
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+//splits function arguments on commas outside of quotes and brackets, leaving each as written.
+fn split_args(text: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut quoted = false;
+
+    if text.trim().is_empty() {
+        return args;
+    }
+
+    for c in text.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            ',' if !quoted && depth == 0 => {
+                args.push(current.clone());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+
+        current.push(c);
+    }
+
+    args.push(current);
+    args
+}
+
+//splits an if into its conditions and actions on pat, but not where pat is inside quotes or in
+//the value of an action that assigns, so !@x>1:@msg = a:b keeps a:b. A part starting with # @ or "
+//after the pat still splits, so the next condition or action should start with one of those.
+fn split_if<'a>(line: &'a str, pat: &str) -> Vec<&'a str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+
+    for (i, c) in line.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        }
+
+        if quoted || !line[i..].starts_with(pat) || i < start {
+            continue;
+        }
+
+        let part = line[start..i].trim_start();
+        let next = line[i + pat.len()..].trim_start();
+
+        if !parts.is_empty()
+            && is_assignment(part)
+            && !matches!(next.chars().next(), Some('#' | '@' | '"'))
+        {
+            continue;
+        }
+
+        parts.push(&line[start..i]);
+        start = i + pat.len();
+    }
+
+    parts.push(&line[start..]);
+    parts
+}
+
+//the first side of a comparison in cond that can never be worked out.
+fn broken_condition(engine: &dyn ExpressionEngine, cond: &str) -> Option<String> {
+    for any in split_top_level(cond, "||") {
+        for part in split_top_level(any, "&&") {
+            let broken = match strip_group(part) {
+                Some(inner) => broken_condition(engine, inner),
+                None => ["!=", "==", "<=", ">=", "<", ">"]
+                    .iter()
+                    .fold(vec![part], |sides, op| {
+                        sides.into_iter().flat_map(|side| side.split(op)).collect()
+                    })
+                    .into_iter()
+                    .find_map(|side| broken_expression(engine, side)),
+            };
+
+            if broken.is_some() {
+                return broken;
+            }
+        }
+    }
+
+    None
+}
+
+//an assigned value, which may pick between two others with cond ? a : b
+fn broken_value(engine: &dyn ExpressionEngine, value: &str) -> Option<String> {
+    match split_ternary(value) {
+        Some((cond, then, other)) => broken_condition(engine, cond)
+            .or_else(|| broken_value(engine, then))
+            .or_else(|| broken_value(engine, other)),
+        None => broken_expression(engine, value),
+    }
+}
+
+//text made only of numbers, @variables, calls and operators that the engine can not work out.
+//variables and calls stand in as 1 so only the shape is checked, and anything with words
+//or quotes in it is text, which is never wrong.
+fn broken_expression(engine: &dyn ExpressionEngine, text: &str) -> Option<String> {
+    let mut dry = String::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '@' {
+            match chars.peek() {
+                Some('{') => while !matches!(chars.next(), Some('}') | None) {},
+                _ => {
+                    while matches!(chars.peek(), Some(n) if !" \0+-<>=().,!#:;^/\\@[]\"*%".contains(*n))
+                    {
+                        chars.next();
+                    }
+                }
+            }
+
+            dry.push('1');
+        } else if c.is_alphabetic() || c == '_' {
+            let mut word = c.to_string();
+
+            while let Some(n) = chars.peek().filter(|n| n.is_alphanumeric() || **n == '_') {
+                word.push(*n);
+                chars.next();
+            }
+
+            if chars.peek() != Some(&'(') {
+                dry.push_str(&word);
+                continue;
+            }
+
+            //a call, with any calls in its arguments, is skipped to its closing bracket.
+            let mut depth = 0;
+
+            for n in &mut chars {
+                match n {
+                    '(' => depth += 1,
+                    ')' if depth == 1 => break,
+                    ')' => depth -= 1,
+                    _ => {}
+                }
+            }
+
+            dry.push('1');
+        } else {
+            dry.push(c);
+        }
+    }
+
+    let arithmetic = dry.chars().any(|c| c.is_ascii_digit())
+        && dry
+            .chars()
+            .all(|c| c.is_ascii_digit() || " \t.+-*/^%()".contains(c));
+
+    match arithmetic && engine.eval(&dry, &VarView::new()).is_err() {
+        true => Some(text.trim().to_string()),
+        false => None,
+    }
+}
+
+//splits text on op where it is outside quotes and brackets.
+fn split_top_level<'a>(text: &'a str, op: &str) -> Vec<&'a str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut depth = 0;
+    let mut quoted = false;
+
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            _ if !quoted && depth == 0 && i >= start && text[i..].starts_with(op) => {
+                parts.push(&text[start..i]);
+                start = i + op.len();
+            }
+            _ => {}
+        }
+    }
+
+    parts.push(&text[start..]);
+    parts
+}
+
+//the inside of (a || b), when the brackets wrap the whole text and it holds && or ||
+fn strip_group(text: &str) -> Option<&str> {
+    let inner = text.trim().strip_prefix('(')?.strip_suffix(')')?;
+    let mut depth = 0;
+
+    //(a) + (b) starts and ends with brackets that do not belong together.
+    for c in inner.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return None,
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+
+    match inner.contains("&&") || inner.contains("||") {
+        true => Some(inner),
+        false => None,
+    }
+}
+
+//a comparison, a lone @variable, a number or a call like visited(shop), joined by && and ||.
+//only these can come before the ? of cond ? a : b, so Ready? 10:30 stays text.
+fn is_condition(text: &str) -> bool {
+    split_top_level(text, "||")
+        .into_iter()
+        .flat_map(|any| split_top_level(any, "&&"))
+        .all(|part| {
+            let part = part.trim();
+
+            if let Some(inner) = strip_group(part) {
+                return is_condition(inner);
+            }
+
+            let variable = part
+                .strip_prefix('@')
+                .filter(|name| !name.is_empty() && !name.contains(|c| VARIABLE_END.contains(c)));
+            let call = part.ends_with(')')
+                && matches!(part.find('('), Some(p) if p > 0
+                    && part[..p].chars().all(|c| c.is_alphanumeric() || c == '_'));
+
+            ["!=", "==", "<=", ">=", "<", ">"]
+                .iter()
+                .any(|op| part.contains(op))
+                || variable.is_some()
+                || call
+                || f64::from_str(part).is_ok()
+        })
+}
+
+//splits cond ? a : b on the first ? and its matching :, outside of quotes and brackets.
+fn split_ternary(text: &str) -> Option<(&str, &str, &str)> {
+    let mut question = None;
+    let mut depth = 0;
+    let mut nested = 0;
+    let mut quoted = false;
+
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            '?' if !quoted && depth == 0 => match question {
+                None => question = Some(i),
+                Some(_) => nested += 1,
+            },
+            ':' if !quoted && depth == 0 && question.is_some() => {
+                if nested == 0 {
+                    let q = question.unwrap();
+
+                    return match is_condition(&text[..q]) {
+                        true => Some((&text[..q], &text[q + 1..i], &text[i + 1..])),
+                        false => None,
+                    };
+                }
+
+                nested -= 1;
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+fn unquote(text: &str) -> String {
+    if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+        text[1..text.len() - 1].to_string()
+    } else {
+        text.to_string()
+    }
+}
+
+//returns the arguments when text is nothing but a call to the named function.
+fn list_call(text: &str, name: &str) -> Option<Vec<String>> {
+    let text = text.trim();
+    let open = name.len();
+
+    if !text.starts_with(name) || !text[open..].starts_with('(') {
+        return None;
+    }
+
+    match matching_paren(&text[open..]) {
+        Some(close) if open + close == text.len() - 1 => {
+            Some(split_args(&text[open + 1..open + close]))
+        }
+        _ => None,
+    }
+}
+
+//splits on a separator of any length, leaving separators inside quotes alone.
+fn split_fields(text: &str, sep: &str, quote: Option<char>) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut i = 0;
+
+    while i < text.len() {
+        let c = text[i..].chars().next().unwrap();
+
+        if Some(c) == quote {
+            quoted = !quoted;
+        } else if !quoted && text[i..].starts_with(sep) {
+            fields.push(current.trim().to_string());
+            current.clear();
+            i += sep.len();
+            continue;
+        } else {
+            current.push(c);
+        }
+
+        i += c.len_utf8();
+    }
+
+    fields.push(current.trim().to_string());
+    fields
+}
+
+fn to_base(value: i64, base: u32) -> String {
+    let mut n = value.unsigned_abs();
+    let mut digits = Vec::new();
+
+    loop {
+        digits.push(std::char::from_digit((n % base as u64) as u32, base).unwrap());
+        n /= base as u64;
+
+        if n == 0 {
+            break;
+        }
+    }
+
+    if value < 0 {
+        digits.push('-');
+    }
+
+    digits.iter().rev().collect::<String>().to_uppercase()
+}
+
+fn bool_string(value: bool) -> String {
+    String::from(if value { "1" } else { "0" })
+}
+
+//*timer start 30 or *timer stop, the seconds may come from a @variable worked out when it runs.
+fn split_timer(args: &str) -> Result<(&str, &str), String> {
+    let (action, secs) = match args.find(char::is_whitespace) {
+        Some(p) => (&args[..p], args[p..].trim()),
+        None => (args, ""),
+    };
+
+    match action {
+        "start" if secs.is_empty() => Err(String::from("*timer start needs a number of seconds")),
+        "start" if !secs.contains('@') && !matches!(f64::from_str(secs), Ok(n) if n >= 0.0) => {
+            Err(format!("{} is not a number of seconds", secs))
+        }
+        "start" | "stop" => Ok((action, secs)),
+        _ => Err(String::from("*timer must be followed by start or stop")),
+    }
+}
+
+//splits a * line into its keyword and the rest of the line, the keyword lowercased so *Include works too.
+//the keyword comes straight after the *, so * with a space after it is still a comment.
+fn split_directive(line: &str) -> (String, &str) {
+    let rest = match line.get(1..) {
+        Some(rest) if !rest.starts_with(char::is_whitespace) => rest,
+        _ => return (String::new(), ""),
+    };
+
+    match rest.find(char::is_whitespace) {
+        Some(p) => (rest[..p].to_lowercase(), rest[p..].trim()),
+        None => (rest.to_lowercase(), ""),
+    }
+}
+
+//a marker only starts a comment after a space, so the // in https://example.com is kept.
+fn comment_can_start(text: &str, i: usize) -> bool {
+    i == 0 || text[..i].ends_with(char::is_whitespace)
+}
+
+//tabs and spaces before a command are dropped so indented blocks still run,
+//while narrative keeps its indent.
+fn unindent_command(line: String) -> String {
+    let trimmed = line.trim_start_matches([' ', '\t'].as_ref());
+
+    if trimmed.len() != line.len() && is_command(trimmed) {
+        trimmed.to_string()
+    } else {
+        line
+    }
+}
+
+//a goto whose label comes from a variable, on a # or > line or as the target of a ? or !
+fn computed_goto(line: &str) -> bool {
+    match line.chars().next() {
+        Some('#' | '>') => line[1..].trim_start().starts_with('@'),
+        Some('?' | '!') => line.contains("#@"),
+        _ => false,
+    }
+}
+
+//the variable and operator of the left side of @gold += 5
+fn split_compound(left: &str) -> Option<(&str, char)> {
+    let left = left.trim_end();
+    let op = left.chars().last().filter(|c| "+-*/?".contains(*c))?;
+    let name = left[1..left.len() - 1].trim();
+
+    match left.starts_with('@') && !name.is_empty() {
+        true => Some((name, op)),
+        false => None,
+    }
+}
+
+//@name = value, but not a comparison like @hp<=5 that also has an =
+fn is_assignment(part: &str) -> bool {
+    match split_assignment(part) {
+        Some((l, _)) => l.starts_with('@') && !l.contains(&['<', '>', '!'][..]),
+        None => false,
+    }
+}
+
+//*include file or %include file
+fn is_include(line: &str) -> bool {
+    (line.starts_with('*') || line.starts_with('%')) && split_directive(line).0 == "include"
+}
+
+fn is_command(line: &str) -> bool {
+    matches!(
+        line.chars().next(),
+        Some(':' | '@' | '!' | '#' | '?' | '^' | '~' | '*' | '`' | '|' | '>' | '<')
+    )
+}
+
+//returns the word right after a ~, used to tell blocks apart from a plain wait.
+fn block_keyword(line: &str) -> String {
+    line[1..]
+        .split_whitespace()
+        .next()
+        .unwrap_or("")
+        .to_lowercase()
+}
+
+//the characters that end a variable's name.
+const VARIABLE_END: &str = " \0+-<>=().,!#:;^/\\@[]\"";
+
+//replaces @name with value only where it is the whole name, so @x leaves @xy alone.
+fn replace_variable(text: &str, name: &str, value: &str) -> String {
+    let token = format!("@{}", name);
+    let mut out = String::new();
+    let mut rest = text;
+
+    while let Some(p) = rest.find(&token) {
+        let after = &rest[p + token.len()..];
+        out.push_str(&rest[..p]);
+
+        match after.chars().next() {
+            Some(c) if !VARIABLE_END.contains(c) => out.push_str(&token),
+            _ => out.push_str(value),
+        }
+
+        rest = after;
+    }
+
+    out.push_str(rest);
+    out
+}
+
+fn parse_variables(line: &str) -> Vec<String> {
+    let arr: nom::IResult<&str, Vec<&str>> = many0(preceded(
+        take_until("@"),
+        preceded(tag("@"), is_not(VARIABLE_END)),
+    ))(line);
+
+    match &arr {
+        Ok(v) => {
+            let mut ret = Vec::new();
+
+            for item in v.1.iter() {
+                ret.insert(0, (*item).to_string())
+            }
+
+            ret
+        }
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    //a stream the story writes to that can still be read once the story is done with it.
+    #[derive(Clone, Default)]
+    struct Transcript(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Transcript {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transcript {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    //a renderer that answers with answers, without color or a terminal width, and what it writes.
+    fn renderer(answers: &str) -> (Renderer, Transcript) {
+        let out = Transcript::default();
+        let mut story = Renderer::new()
+            .with_input(Box::new(Cursor::new(answers.to_string())))
+            .with_output(Box::new(out.clone()));
+        story.color = ColorMode::Never;
+        story.width_query = || None;
+        (story, out)
+    }
+
+    //a clock that only moves when the test moves it.
+    #[derive(Clone, Default)]
+    struct TestClock(Rc<std::cell::Cell<Duration>>);
+
+    impl Clock for TestClock {
+        fn now(&self) -> Duration {
+            self.0.get()
+        }
+    }
+
+    impl TestClock {
+        fn advance(&self, secs: u64) {
+            self.0.set(self.0.get() + Duration::from_secs(secs));
+        }
+    }
+
+    fn load(text: &str, answers: &str) -> (Renderer, Transcript) {
+        let (mut story, out) = renderer(answers);
+        story.processfile(Cursor::new(text.to_string())).unwrap();
+        (story, out)
+    }
+
+    #[test]
+    fn advancing_to_a_label_keeps_what_was_set_before_it() {
+        let text =
+            "@gold = 0\nHidden\n@count = 2\n^i How many?:@count\n@gold = 10\n:target\nYou have @gold\n";
+        let (mut story, out) = load(text, "");
+        story.advance_to = Some(String::from("target"));
+
+        story.run().unwrap();
+        assert_eq!(story.advance_to, None);
+        assert_eq!(story.variable("gold"), Some(&Value::Num(10.0)));
+        assert_eq!(story.variable("count"), Some(&Value::Num(2.0)));
+        assert_eq!(out.text(), "You have 10\n");
+    }
+
+    #[test]
+    fn while_loops_stop_at_their_limit() {
+        let (mut story, _) = load(
+            "@i = 0\n~while @i >= 0 limit 5\n@i = @i + 1\n~endwhile\n",
+            "",
+        );
+        assert!(matches!(
+            story.run(),
+            Err(StoryError::LoopLimit { line: 2, limit: 5 })
+        ));
+
+        let (mut story, _) = load(
+            "@i = 0\n~while @i < 3 limit 1000\n@i = @i + 1\n~endwhile\n",
+            "",
+        );
+        story.run().unwrap();
+        assert_eq!(story.variable("i"), Some(&Value::Num(3.0)));
+
+        let (mut story, _) = load("@i = 0\n~while @i >= 0\n@i = @i + 1\n~endwhile\n", "");
+        story.loop_limit = 10;
+        assert!(matches!(
+            story.run(),
+            Err(StoryError::LoopLimit { line: 2, limit: 10 })
+        ));
+    }
+
+    #[test]
+    fn list_inputs_are_declared_and_read_as_joined_text() {
+        let (mut story, _) = load("^l:items Enter an item\nYou carry @items\n", "");
+        assert_eq!(story.lists["items"], Vec::<String>::new());
+        assert_eq!(
+            split_list_input(" @items Enter an item "),
+            ("items", "Enter an item")
+        );
+
+        story.lists.insert(
+            String::from("items"),
+            vec![String::from("sword"), String::from("rope")],
+        );
+        assert_eq!(
+            story.process_variables("You carry @items").unwrap(),
+            "You carry sword, rope"
+        );
+    }
+
+    #[test]
+    fn list_input_stops_at_a_blank_answer_or_the_end() {
+        let (mut story, _) = load(
+            "^l:items Enter an item\nDone\n",
+            "sword\nshield\nrope\n\nignored\n",
+        );
+        story.run().unwrap();
+        assert_eq!(
+            story.list("items"),
+            Some(&vec![
+                String::from("sword"),
+                String::from("shield"),
+                String::from("rope")
+            ])
+        );
+
+        let (mut story, _) = load("^l:items Enter an item\n", "map\n");
+        story.run().unwrap();
+        assert_eq!(story.list("items"), Some(&vec![String::from("map")]));
+    }
+
+    #[test]
+    fn visited_is_true_once_a_label_is_passed() {
+        let text = "@before = visited(intro)\n:intro\nHello\n@after = visited(intro)\n";
+        let (mut story, _) = load(text, "");
+
+        story.run().unwrap();
+        assert_eq!(story.variable("before"), Some(&Value::Num(0.0)));
+        assert_eq!(story.variable("after"), Some(&Value::Num(1.0)));
+    }
+
+    //a front end on the other end of a channel, like a GUI thread would be.
+    struct ChannelIo {
+        events: std::sync::mpsc::Sender<OutputEvent>,
+        answers: std::sync::mpsc::Receiver<String>,
+    }
+
+    impl StoryIo for ChannelIo {
+        fn emit(&mut self, event: OutputEvent) {
+            self.events.send(event).unwrap();
+        }
+
+        fn read_line(&mut self) -> Option<String> {
+            self.answers.recv().ok()
+        }
+    }
+
+    #[test]
+    fn a_question_block_emits_its_events_in_order() {
+        let (events, shown) = std::sync::mpsc::channel();
+        let (answer, answers) = std::sync::mpsc::channel();
+        let mut story = Renderer::new().with_io(Box::new(ChannelIo { events, answers }));
+        story.color = ColorMode::Never;
+        let text = "Which way?\n?North:#north\n?South:#north\n:north\n";
+        story.processfile(Cursor::new(text.to_string())).unwrap();
+
+        answer.send(String::from("1")).unwrap();
+        story.run().unwrap();
+        drop(story);
+
+        let option = |n: usize, text: &str| OutputEvent::MenuOption {
+            n,
+            hotkey: None,
+            text: text.to_string(),
+            display: format!("{}. {}", n, text),
+        };
+        assert_eq!(
+            shown.iter().collect::<Vec<OutputEvent>>(),
+            vec![
+                OutputEvent::Narrative(String::from("Which way?")),
+                option(1, "North"),
+                option(2, "South"),
+                OutputEvent::Prompt(String::from("Enter a number from 1 to 2")),
+            ]
+        );
+    }
+
+    #[test]
+    fn semicolon_comments_are_stripped_when_added() {
+        let text = "@gold = 5 ; starting gold\n@name = \"a ; b\"\n";
+        let (mut story, _) = renderer("");
+        story.comment_markers.push(String::from(";"));
+        story.processfile(Cursor::new(text.to_string())).unwrap();
+
+        story.run().unwrap();
+        assert_eq!(story.variable("gold"), Some(&Value::Num(5.0)));
+        assert_eq!(
+            story.variable("name"),
+            Some(&Value::Str(String::from("\"a ; b\"")))
+        );
+    }
+
+    #[test]
+    fn star_with_a_space_after_it_is_a_comment() {
+        let text = "* timer notes\n* restart here later\n* include the map\n* debug\nHello\n";
+        let (mut story, out) = load(text, "");
+
+        story.run().unwrap();
+        assert_eq!(out.text(), "Hello\n");
+    }
+
+    #[test]
+    fn undo_goes_back_to_before_the_last_choice() {
+        let text = "@gold = 5\n?Buy:#buy\n?Leave:#end\n:buy\n@gold = 0\n:end\n";
+        let (mut story, _) = load(text, "1\n");
+
+        story.run().unwrap();
+        assert_eq!(story.variable("gold"), Some(&Value::Num(0.0)));
+
+        assert!(story.undo());
+        assert_eq!(story.variable("gold"), Some(&Value::Num(5.0)));
+        assert_eq!(story.index, 1);
+        assert!(!story.undo());
+    }
+
+    #[test]
+    fn enum_variables_only_take_their_values() {
+        let text = "@difficulty:enum(easy,normal,hard) = normal\n@difficulty = hard\n";
+        let (mut story, _) = load(text, "");
+        story.run().unwrap();
+        assert_eq!(
+            story.variable("difficulty"),
+            Some(&Value::Str(String::from("hard")))
+        );
+
+        let text = "@difficulty:enum(easy,normal,hard) = normal\n@difficulty = brutal\n";
+        let (mut story, _) = load(text, "");
+        assert!(matches!(
+            story.run(),
+            Err(StoryError::InvalidEnumValue { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn menu_goes_back_to_the_last_question() {
+        let text = "@looks = 0\n:shop\nShop\n?Look around:#look\n?Leave:#leave\n:look\n@looks = @looks + 1\n#menu\n:leave\nBye\n";
+        let (mut story, out) = load(text, "1\n2\n");
+
+        story.run().unwrap();
+        assert_eq!(story.variable("looks"), Some(&Value::Num(1.0)));
+        assert_eq!(out.text().matches("Shop").count(), 1);
+        assert_eq!(out.text().matches("1. Look around").count(), 2);
+        assert!(out.text().ends_with("Bye\n"));
+    }
+
+    #[test]
+    fn numbers_round_trip_through_other_bases() {
+        let (story, _) = load("", "");
+
+        assert_eq!(
+            story.eval_expression("tobase(255, 16)").unwrap(),
+            Value::Str(String::from("FF"))
+        );
+        assert_eq!(
+            story.eval_expression("frombase(\"FF\", 16)").unwrap(),
+            Value::Num(255.0)
+        );
+        assert_eq!(
+            story.eval_expression("tobase(5, 2)").unwrap(),
+            Value::Num(101.0)
+        );
+        assert_eq!(
+            story.eval_expression("frombase(tobase(5, 2), 2)").unwrap(),
+            Value::Num(5.0)
+        );
+        assert!(matches!(
+            story.eval_expression("frombase(\"12\", 2)"),
+            Err(StoryError::Function { .. })
+        ));
+        assert!(matches!(
+            story.eval_expression("tobase(5, 37)"),
+            Err(StoryError::Function { .. })
+        ));
+    }
+
+    #[test]
+    fn split_fills_a_list_on_any_separator() {
+        let text = "@row = \"sword|shield|'rope|hook'\"\n@items = split(@row, \"|\", \"'\")\n@pairs = split(\"a::b::c\", \"::\")\n";
+        let (mut story, _) = load(text, "");
+
+        story.run().unwrap();
+        assert_eq!(
+            story.list("items"),
+            Some(&vec![
+                String::from("sword"),
+                String::from("shield"),
+                String::from("rope|hook")
+            ])
+        );
+        assert_eq!(
+            story.list("pairs"),
+            Some(&vec![
+                String::from("a"),
+                String::from("b"),
+                String::from("c")
+            ])
+        );
+    }
+
+    #[test]
+    fn finishing_waits_for_enter_only_when_asked() {
+        for (pause, left) in [(true, "next"), (false, "")].iter() {
+            let (mut story, out) = load("Hello\n", "\nnext\n");
+            story.pause_on_finish = *pause;
+
+            story.run().unwrap();
+            assert_eq!(story.io.read_line().as_deref(), Some(*left));
+            assert_eq!(out.text().contains("Press Enter"), *pause);
+        }
+    }
+
+    #[test]
+    fn narrative_wraps_at_the_queried_width() {
+        let words = "the quick brown fox jumps over the lazy dog and keeps on running far away";
+        let (mut story, out) = load(&format!("{}\n", words), "");
+        story.width_query = || Some(40);
+
+        story.run().unwrap();
+        let text = out.text();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| line.chars().count() <= 40));
+        assert_eq!(lines.join(" "), words);
+    }
+
+    #[test]
+    fn defaults_can_use_each_other_but_not_in_a_circle() {
+        let (mut story, _) = load("@hp = @maxhp\n@maxhp = 100\n", "");
+        assert_eq!(story.variable("hp"), Some(&Value::Num(100.0)));
+        story.run().unwrap();
+        assert_eq!(story.variable("maxhp"), Some(&Value::Num(100.0)));
+
+        let (mut story, _) = renderer("");
+        let errors = story
+            .processfile(Cursor::new(String::from("@a = @b + 1\n@b = @a + 1\n")))
+            .unwrap_err();
+        assert!(errors
+            .iter()
+            .any(|e| matches!(e, StoryError::CyclicDefault { .. })));
+    }
+
+    #[test]
+    fn tables_line_up_their_columns() {
+        let text = "*table header\nItem|Count\nSword|1\nHealing potion|12\n*endtable\n";
+        let (mut story, out) = load(text, "");
+
+        story.run().unwrap();
+        assert_eq!(
+            out.text(),
+            "Item           | Count\n---------------+------\nSword          | 1\nHealing potion | 12\n"
+        );
+    }
+
+    #[test]
+    fn restart_plays_again_with_fresh_variables() {
+        let text = "@plays = 0\n:start\n@plays = @plays + 1\nPlay @plays\n?Again:#again\n?Stop:#stop\n:again\n*restart\n:stop\n";
+        let (mut story, out) = load(text, "1\n2\n");
+
+        story.run().unwrap();
+        assert_eq!(out.text().matches("Play 1").count(), 2);
+        assert_eq!(story.variable("plays"), Some(&Value::Num(1.0)));
+    }
+
+    #[test]
+    fn hotkeys_pick_options_and_must_be_unique() {
+        let text = "?[f]Fight:#battle\n?[r]Run:#away\n:battle\nFought\n#end\n:away\nRan\n:end\n";
+        let (mut story, out) = load(text, "r\n");
+
+        story.run().unwrap();
+        assert!(out.text().contains("f) Fight"));
+        assert!(out.text().ends_with("Ran\n"));
+
+        let (mut story, _) = renderer("");
+        let errors = story
+            .processfile(Cursor::new(String::from("?[f]Fight:#a\n?[f]Flee:#a\n:a\n")))
+            .unwrap_err();
+        assert!(matches!(errors[..], [StoryError::DuplicateHotkey { .. }]));
+    }
+
+    #[test]
+    fn ternaries_pick_a_value() {
+        let text = "@hp = 80\n@status = @hp > 50 ? \"healthy\" : \"hurt\"\n@hp = 10\n@low = @hp > 50 ? \"healthy\" : \"hurt\"\n@rank = @hp > 50 ? \"high\" : @hp > 5 ? \"middle\" : \"low\"\n";
+        let (mut story, _) = load(text, "");
+
+        story.run().unwrap();
+        assert_eq!(
+            story.variable("status"),
+            Some(&Value::Str(String::from("healthy")))
+        );
+        assert_eq!(
+            story.variable("low"),
+            Some(&Value::Str(String::from("hurt")))
+        );
+        assert_eq!(
+            story.variable("rank"),
+            Some(&Value::Str(String::from("middle")))
+        );
+    }
+
+    #[test]
+    fn text_with_a_question_mark_and_colon_is_not_a_ternary() {
+        let text = "@time = 0\n@time = Ready? 10:30\n@hp = 80\n@state = @hp > 50 ? fine : hurt\nAt @time, @state\n";
+        let (mut story, out) = load(text, "");
+
+        story.run().unwrap();
+        assert_eq!(out.text(), "At Ready? 10:30, fine\n");
+    }
+
+    #[test]
+    fn no_color_strips_codes_unless_forced() {
+        assert!(!terminal_color(true));
+
+        //a terminal that can show colors, so only no_color decides.
+        let text = "[color red]Danger[/color]\n";
+        let (mut story, out) = load(text, "");
+        story.color = ColorMode::Auto;
+        story.color_support = |no_color| !no_color;
+        story.no_color = true;
+        story.run().unwrap();
+        assert_eq!(out.text(), "Danger\n");
+
+        let (mut story, out) = load(text, "");
+        story.color = ColorMode::Auto;
+        story.color_support = |no_color| !no_color;
+        story.no_color = false;
+        story.run().unwrap();
+        assert_eq!(out.text(), "\x1b[31mDanger\x1b[0m\n");
+
+        let (mut story, out) = load(text, "");
+        story.color = ColorMode::Always;
+        story.run().unwrap();
+        assert_eq!(out.text(), "\x1b[31mDanger\x1b[0m\n");
+    }
+
+    #[test]
+    fn elapsed_follows_the_clock() {
+        let (mut story, _) = load("Start\n@taken = elapsed()\n", "");
+        let clock = TestClock::default();
+        story.clock = Box::new(clock.clone());
+
+        story.step().unwrap();
+        clock.advance(42);
+        story.run().unwrap();
+        assert_eq!(story.variable("taken"), Some(&Value::Num(42.0)));
+    }
+
+    #[test]
+    fn long_lines_are_refused_or_cut() {
+        let text = format!("Short\n{}\nAfter\n", "é".repeat(20));
+
+        let (mut story, _) = renderer("");
+        story.max_line_length = 16;
+        let errors = story.processfile(Cursor::new(text.clone())).unwrap_err();
+        assert!(matches!(
+            errors[..],
+            [StoryError::LineTooLong {
+                line: 2,
+                length: 40,
+                limit: 16
+            }]
+        ));
+
+        let warnings = Transcript::default();
+        let (story, out) = renderer("");
+        let mut story = story.with_error(Box::new(warnings.clone()));
+        story.max_line_length = 15;
+        story.truncate_long_lines = true;
+        story.processfile(Cursor::new(text)).unwrap();
+        story.run().unwrap();
+        assert_eq!(out.text(), format!("Short\n{}\nAfter\n", "é".repeat(7)));
+        assert!(warnings.text().starts_with("Warning: line 2"));
+    }
+
+    #[test]
+    fn spaces_around_tokens_are_trimmed() {
+        let text = "@gold   =   5\n@name = \"  Ann  \"\n!@gold == 5  :  #rich\nPoor\n:rich\nYou have @gold\n";
+        let (mut story, out) = load(text, "");
+
+        story.run().unwrap();
+        assert_eq!(story.variable("gold"), Some(&Value::Num(5.0)));
+        assert_eq!(
+            story.variable("name"),
+            Some(&Value::Str(String::from("\"  Ann  \"")))
+        );
+        assert_eq!(out.text(), "You have 5\n");
+    }
+
+    #[test]
+    fn every_assignment_declares_its_name() {
+        let text = "@note = a = b\n@sum = 1 + 2 * 3\n@later = @sum\n";
+        let (mut story, _) = load(text, "");
+
+        assert!(story.variable("note").is_some());
+        story.run().unwrap();
+        assert_eq!(
+            story.variable("note"),
+            Some(&Value::Str(String::from("a = b")))
+        );
+        assert_eq!(story.variable("sum"), Some(&Value::Num(7.0)));
+        assert_eq!(story.variable("later"), Some(&Value::Num(7.0)));
+    }
+
+    #[test]
+    fn label_case_only_matters_when_asked() {
+        let text = "#Intro\nSkipped\n:intro\nHello\n";
+
+        let (mut story, out) = renderer("");
+        story.case_insensitive_labels = true;
+        story.processfile(Cursor::new(text.to_string())).unwrap();
+        story.run().unwrap();
+        assert_eq!(out.text(), "Hello\n");
+
+        let (mut story, _) = load(text, "");
+        assert!(story.validate().is_err());
+        assert!(story.run().is_err());
+
+        let (mut story, _) = renderer("");
+        story.case_insensitive_labels = true;
+        let errors = story
+            .processfile(Cursor::new(String::from(":intro\n:Intro\n")))
+            .unwrap_err();
+        assert!(matches!(
+            errors[..],
+            [StoryError::DuplicateLabel { second_line: 2, .. }]
+        ));
+    }
+
+    #[test]
+    fn every_broken_line_is_reported_at_once() {
+        let text = "?Go somewhere\n:start\n*timer start soon\n@i = 1 + * 2\n";
+        let (mut story, _) = renderer("");
+        let errors = story
+            .processfile(Cursor::new(String::from(text)))
+            .unwrap_err();
+
+        let lines: Vec<Option<usize>> = errors.iter().map(StoryError::line).collect();
+        assert_eq!(lines, vec![Some(1), Some(3), Some(4)]);
+        assert!(errors.iter().all(StoryError::is_fatal));
+    }
+
+    #[test]
+    fn format_specs_pad_values() {
+        let text = "@gold = 42\n@name = Ann\n[{{@gold:>6}}]\n[{{@name:<6}}]\n[{{@name:-^7}}]\n[{{@gold}}]\n";
+        let (mut story, out) = load(text, "");
+
+        story.run().unwrap();
+        assert_eq!(out.text(), "[    42]\n[Ann   ]\n[--Ann--]\n[42]\n");
+    }
+
+    #[test]
+    fn pattern_input_asks_until_it_matches() {
+        let text =
+            "@email = \"\"\n^r:email /^[^@ ]+@[^@ ]+\\.[a-z]+$/ Your email?\nSent to @email\n";
+        let (mut story, out) = load(text, "not an email\nann@example.com\n");
+
+        story.run().unwrap();
+        assert_eq!(
+            story.variable("email"),
+            Some(&Value::Str(String::from("ann@example.com")))
+        );
+        assert_eq!(out.text().matches("Your email?").count(), 2);
+        assert!(out.text().ends_with("Sent to ann@example.com\n"));
+    }
+
+    #[test]
+    fn dumps_show_the_comment_after_a_variable() {
+        let text = "@gold = 0 // the player's money\n@hp = 10\n@gold = 3\n";
+        let (mut story, _) = load(text, "");
+
+        story.run().unwrap();
+        assert_eq!(
+            story.dump_variables(),
+            "@gold = 3  // the player's money\n@hp = 10"
+        );
+    }
+
+    #[test]
+    fn lenient_stories_fill_in_whole_names_only() {
+        let text = "@xy = 5\n!@x == 0 && @xy == 5:#both\nNeither\n#end\n:both\nBoth @xy\n:end\n";
+        let (mut story, out) = load(text, "");
+        story.lenient = true;
+
+        story.run().unwrap();
+        assert_eq!(out.text(), "Both 5\n");
+
+        let (mut story, out) = load("@n = 3\n@name = Ann\nHi @name @n\n", "");
+        story.run().unwrap();
+        assert_eq!(out.text(), "Hi Ann 3\n");
+    }
+
+    #[test]
+    fn dialogue_names_its_speaker() {
+        let text = "@Alice: Hello there\n@Bob: Hi, Alice\n@mood = 1\n";
+        let (mut story, out) = load(text, "");
+
+        story.run().unwrap();
+        assert_eq!(out.text(), "Alice: Hello there\nBob: Hi, Alice\n");
+        assert_eq!(
+            story.variable("_speaker"),
+            Some(&Value::Str(String::from("Bob")))
+        );
+        assert_eq!(story.variable("mood"), Some(&Value::Num(1.0)));
+    }
+
+    #[test]
+    fn dialogue_may_say_an_equals_sign() {
+        let text = "@mood:enum(calm,angry) = calm\n@Teacher: 2 + 2 = 4\n@mood = angry\n";
+        let (mut story, out) = load(text, "");
+
+        story.run().unwrap();
+        assert_eq!(out.text(), "Teacher: 2 + 2 = 4\n");
+        assert_eq!(
+            story.variable("_speaker"),
+            Some(&Value::Str(String::from("Teacher")))
+        );
+        assert_eq!(story.variable("Teacher"), None);
+    }
+
+    #[test]
+    fn seeded_rolls_repeat() {
+        let text = "@roll = rand(1, 6)\n@chance = randf(0, 1)\n";
+        let roll = || {
+            let (story, _) = renderer("");
+            let mut story = story.with_seed(7);
+            story.processfile(Cursor::new(text.to_string())).unwrap();
+            story.run().unwrap();
+            (
+                story.variable("roll").cloned(),
+                story.variable("chance").cloned(),
+            )
+        };
+
+        let (dice, chance) = roll();
+        assert_eq!((dice.clone(), chance.clone()), roll());
+
+        match (dice, chance) {
+            (Some(Value::Num(d)), Some(Value::Num(c))) => {
+                assert!((1.0..=6.0).contains(&d) && d.fract() == 0.0);
+                assert!((0.0..1.0).contains(&c));
+            }
+            other => panic!("rolled {:?}", other),
+        }
+    }
+
+    //a clock where a second passes every time it is read.
+    struct TickingClock(std::cell::Cell<Duration>);
+
+    impl Clock for TickingClock {
+        fn now(&self) -> Duration {
+            self.0.set(self.0.get() + Duration::from_secs(1));
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn a_timeout_stops_an_endless_loop() {
+        let (mut story, _) = load(":loop\n@i = 1\n#loop\n", "");
+        story.clock = Box::new(TickingClock(Default::default()));
+
+        assert!(matches!(
+            story.run_with_timeout(Duration::from_secs(30)),
+            Err(StoryError::Timeout { .. })
+        ));
+
+        let (mut story, out) = load("Quick\n", "");
+        story.clock = Box::new(TickingClock(Default::default()));
+        story.run_with_timeout(Duration::from_secs(30)).unwrap();
+        assert_eq!(out.text(), "Quick\n");
+    }
+
+    #[test]
+    fn a_quiz_reports_its_score_and_ending() {
+        let text = "Capital of France?\n?*Paris:#second\n?Rome:#second\n:second\nTwo and two?\n?Three:#done\n?*Four:#done\n:done\n";
+        let (mut story, _) = load(text, "1\n1\n");
+
+        story.run().unwrap();
+        let result = story.result();
+        assert_eq!(result.score, Some(1.0));
+        assert_eq!(result.ending.as_deref(), Some("done"));
+        assert_eq!(
+            result.answers.iter().map(|a| a.correct).collect::<Vec<_>>(),
+            vec![Some(true), Some(false)]
+        );
+
+        let json = serde_json::to_string(&result).unwrap();
+        assert!(json.contains("\"ending\":\"done\""));
+        assert!(json.contains("\"text\":\"Paris\""));
+    }
+
+    #[test]
+    fn the_edition_picks_which_chapter_is_included() {
+        let dir = std::env::temp_dir().join("storyrender_edition_include");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("chapter_free.txt"), "Free chapter\n").unwrap();
+        std::fs::write(dir.join("chapter_full.txt"), "Full chapter\n").unwrap();
+        let text = "*include chapter_@{edition}.txt\n";
+
+        for edition in ["free", "full"].iter() {
+            let (mut story, out) = renderer("");
+            story.base_dir = dir.clone();
+            story.set_variable("edition", Value::infer(edition));
+            story.processfile(Cursor::new(text.to_string())).unwrap();
+            story.run().unwrap();
+            assert_eq!(out.text().to_lowercase(), format!("{} chapter\n", edition));
+        }
+
+        let (mut story, _) = renderer("");
+        story.base_dir = dir.clone();
+        story.set_variable("edition", Value::infer("deluxe"));
+        let errors = story
+            .processfile(Cursor::new(text.to_string()))
+            .unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(errors[..], [StoryError::Include { line: 1, .. }]));
+    }
+
+    #[test]
+    fn timer_runs_down_with_the_clock() {
+        let text = "*timer start 10\n!@_timer == 0:#late\nIn time\n#end\n:late\nToo late\n:end\n";
+
+        for (wait, shown) in [(5, "In time\n"), (11, "Too late\n")].iter() {
+            let (mut story, out) = load(text, "");
+            let clock = TestClock::default();
+            story.clock = Box::new(clock.clone());
+
+            story.step().unwrap();
+            clock.advance(*wait);
+            story.run().unwrap();
+            assert_eq!(out.text(), *shown);
+        }
+    }
+
+    #[test]
+    fn broken_timers_are_found_when_loading() {
+        for line in ["*timer start", "*timer start soon", "*timer foo"].iter() {
+            let (mut story, _) = renderer("");
+            let errors = story
+                .processfile(Cursor::new(line.to_string()))
+                .unwrap_err();
+            assert!(matches!(
+                errors[..],
+                [StoryError::Malformed { line: 1, .. }]
+            ));
+        }
+
+        let (mut story, _) = load("@secs = \"soon\"\n*timer start @secs\n", "");
+        assert!(matches!(
+            story.run(),
+            Err(StoryError::Malformed { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn replace_swaps_text() {
+        let text =
+            "@name = \"Sir Ann\"\n@title = replace(@name, \"Sir\", \"Dame\")\nHello @title\n";
+        let (mut story, out) = load(text, "");
+
+        story.run().unwrap();
+        assert_eq!(out.text(), "Hello Dame Ann\n");
+    }
+
+    #[test]
+    fn replace_changes_every_match() {
+        let text = "@path = \"a_b_c\"\n@path = replace(@path, \"_\", \" \")\n";
+        let (mut story, _) = load(text, "");
+
+        story.run().unwrap();
+        assert_eq!(
+            story.variable("path"),
+            Some(&Value::Str(String::from("a b c")))
+        );
+    }
+
+    #[test]
+    fn replace_needs_something_to_search_for() {
+        let (mut story, _) = load("@name = Ann\n@name = replace(@name, \"\", \"x\")\n", "");
+
+        match story.run() {
+            Err(StoryError::Function { name, message, .. }) => {
+                assert_eq!(name, "replace");
+                assert!(message.contains("not empty"));
+            }
+            other => panic!("gave {:?}", other),
+        }
+    }
+
+    #[test]
+    fn squeeze_keeps_commas_and_joins_spaces() {
+        let text = "@name = \"\"\n^s Name?:@name\n@short = squeeze(@name)\n@size = len(squeeze(@name))\n@literal = squeeze(\"  a ,  b  \")\n";
+        let (mut story, _) = renderer("  Ann,   Bo  Lee  \n");
+        story.trim_input = false;
+        story.processfile(Cursor::new(text.to_string())).unwrap();
+
+        story.run().unwrap();
+        assert_eq!(
+            story.variable("short"),
+            Some(&Value::Str(String::from("Ann, Bo Lee")))
+        );
+        assert_eq!(story.variable("size"), Some(&Value::Num(11.0)));
+        assert_eq!(
+            story.variable("literal"),
+            Some(&Value::Str(String::from("a , b")))
+        );
+    }
+
+    #[test]
+    fn strict_numbers_refuse_text_operands() {
+        let text = "@name = Ann\n!@name > 3:#big\n:big\n";
+
+        let (mut story, _) = load(text, "");
+        story.strict_numbers = true;
+        match story.run() {
+            Err(StoryError::NotANumber { operand, line: 2 }) => assert_eq!(operand, "@name"),
+            other => panic!("gave {:?}", other),
+        }
+
+        let (mut story, _) = load(text, "");
+        assert!(matches!(
+            story.run(),
+            Err(StoryError::TypeMismatch { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn a_label_renders_up_to_its_next_jump() {
+        let text = "@gold = 5\n:shop\nWelcome in.\nYou have @gold gold.\n?Buy:#buy\n:buy\nSold\n";
+        let (mut story, _) = load(text, "");
+
+        story.step().unwrap();
+        assert_eq!(
+            story.render_label("shop").unwrap(),
+            "Welcome in.\nYou have 5 gold."
+        );
+        assert!(story.render_label("nowhere").is_err());
+    }
+
+    #[test]
+    fn directive_keywords_ignore_case() {
+        let dir = std::env::temp_dir().join("storyrender_include_case");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("Part.txt"), "Included\n").unwrap();
+
+        let shown: Vec<String> = [
+            "*include Part.txt",
+            "*Include Part.txt",
+            "*INCLUDE Part.txt",
+        ]
+        .iter()
+        .map(|line| {
+            let (mut story, out) = renderer("");
+            story.base_dir = dir.clone();
+            story
+                .processfile(Cursor::new(format!("{}\n*HUD Keep Case\n", line)))
+                .unwrap();
+            story.run().unwrap();
+            out.text()
+        })
+        .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(shown[0], "Included\nKeep Case\n");
+        assert!(shown.iter().all(|text| *text == shown[0]));
+    }
+
+    #[test]
+    fn handlers_run_their_line_on_their_own() {
+        let text = "@gold = 1\n#shop\nSkipped\n:shop\n!@gold == 1:@gold = 5\n@gold = @gold * 2\n";
+        let (mut story, out) = load(text, "");
+
+        story.index = 1;
+        story.handle_goto(None).unwrap();
+        assert_eq!(story.index, 3);
+
+        story.index = 4;
+        story.handle_if().unwrap();
+        assert_eq!(story.variable("gold"), Some(&Value::Num(5.0)));
+        assert_eq!(story.index, 5);
+
+        story.handle_assign(None).unwrap();
+        assert_eq!(story.variable("gold"), Some(&Value::Num(10.0)));
+
+        story.handle_text("You have @gold gold.").unwrap();
+        assert_eq!(out.text(), "You have 10 gold.\n");
+        assert_eq!(story.index, 7);
+    }
+
+    #[test]
+    fn broken_lines_are_errors_instead_of_panics() {
+        let lines = [
+            "#nowhere",
+            "!1 == 1:\"never closed",
+            "!1 == 1:@gold",
+            "!1 == 1 == 1:#start",
+            "!1 == 1:@undeclared = 2",
+        ];
+
+        for line in lines.iter() {
+            let (mut story, _) = load(&format!(":start\n{}\n", line), "");
+
+            match story.run() {
+                Err(StoryError::Malformed { line: 2, .. })
+                | Err(StoryError::MissingVariable { line: 2, .. }) => {}
+                other => panic!("{} gave {:?}", line, other),
+            }
+        }
+    }
+
+    #[test]
+    fn comments_leave_urls_alone() {
+        let text = "See https://example.com for more\nA path//like this\nVisible // hidden note\n@gold = 5 // starting gold\n";
+        let (mut story, out) = load(text, "");
+
+        story.run().unwrap();
+        assert_eq!(
+            out.text(),
+            "See https://example.com for more\nA path//like this\nVisible\n"
+        );
+        assert_eq!(story.variable("gold"), Some(&Value::Num(5.0)));
+    }
+
+    #[test]
+    fn marked_options_count_correct_answers() {
+        let text = "?Rome:#next\n?*Paris:#next\n:next\n?*Four:#done\n?Five:#done\n:done\n";
+        let (mut story, _) = load(text, "2\n2\n");
+
+        story.run().unwrap();
+        assert_eq!(story.variable("_correct"), Some(&Value::Num(1.0)));
+        assert_eq!(story.variable("_incorrect"), Some(&Value::Num(1.0)));
+    }
+
+    #[test]
+    fn stories_load_from_a_zip_with_their_includes() {
+        use std::io::Write;
+
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default();
+        zip.start_file("story/main.txt", options).unwrap();
+        zip.write_all(b"Start\n*include part.txt\n").unwrap();
+        zip.start_file("story/part.txt", options).unwrap();
+        zip.write_all(b"From the archive\n").unwrap();
+        let data = zip.finish().unwrap().into_inner();
+
+        let path = std::env::temp_dir().join("storyrender_archive.zip");
+        std::fs::write(&path, data).unwrap();
+
+        let (mut story, out) = renderer("");
+        story.load_from_archive(&path, "story/main.txt").unwrap();
+        story.run().unwrap();
+        assert_eq!(out.text(), "Start\nFrom the archive\n");
+
+        let (mut story, _) = renderer("");
+        let errors = story
+            .load_from_archive(&path, "story/gone.txt")
+            .unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(errors[..], [StoryError::Archive { .. }]));
+    }
+
+    #[test]
+    fn a_question_block_lists_where_each_option_goes() {
+        let text = ":start\nWhere to?\n?Left:#left\n?Right:#right\n:left\nL\n:right\nR\n";
+        let (story, _) = load(text, "");
+
+        assert_eq!(
+            story.outgoing_edges("start"),
+            vec![
+                (String::from("Left"), String::from("left")),
+                (String::from("Right"), String::from("right")),
+            ]
+        );
+        assert!(story.outgoing_edges("nowhere").is_empty());
+    }
+
+    #[test]
+    fn tab_indented_commands_still_run() {
+        let text = "@gold = 1\n\t@gold = 7\n\t!@gold == 7:#rich\nPoor\n:rich\n\tRich\n";
+        let (mut story, out) = load(text, "");
+
+        story.run().unwrap();
+        assert_eq!(story.variable("gold"), Some(&Value::Num(7.0)));
+        assert_eq!(out.text(), "\tRich\n");
+    }
+
+    #[test]
+    fn strict_flow_rejects_gotos_to_a_variable() {
+        let text = "@next = end\n#@next\n:end\nDone\n";
+
+        let (mut story, _) = renderer("");
+        story.strict_flow = true;
+        let errors = story
+            .processfile(Cursor::new(text.to_string()))
+            .unwrap_err();
+        assert!(matches!(
+            errors[..],
+            [StoryError::Malformed { line: 2, .. }]
+        ));
+
+        let (mut story, out) = load(text, "");
+        story.run().unwrap();
+        assert_eq!(out.text(), "Done\n");
+    }
+
+    #[test]
+    fn questions_in_a_false_if_are_skipped() {
+        let text = "@gold = 1\n~if @gold > 5\nBuy something?\n?Yes:#shop\n?No:#end\n~endif\nYou walk on.\n#end\n:shop\nShop\n:end\n";
+        let (mut story, out) = load(text, "");
+
+        story.run().unwrap();
+        assert_eq!(out.text(), "You walk on.\n");
+    }
+
+    #[test]
+    fn options_use_the_chosen_format() {
+        let text =
+            "*options [{}] {}\n?Left:#left\n?Right:#right\n:left\nL\n#end\n:right\nR\n:end\n";
+        let (mut story, out) = load(text, "2\n");
+
+        story.run().unwrap();
+        assert!(out.text().contains("[1] Left\n[2] Right\n"));
+        assert!(out.text().ends_with("R\n"));
+    }
+
+    #[test]
+    fn a_number_hotkey_clashing_with_an_option_is_reported() {
+        let text = "Pick one\n?North:#a\n?South:#a\n?[2]East:#a\n:a\n";
+        let (mut story, _) = renderer("");
+
+        let errors = story
+            .processfile(Cursor::new(text.to_string()))
+            .unwrap_err();
+        match &errors[..] {
+            [StoryError::DuplicateHotkey {
+                key: '2',
+                first_line,
+                line,
+            }] => assert_eq!((*first_line, *line), (3, 4)),
+            other => panic!("gave {:?}", other),
+        }
+    }
+
+    #[test]
+    fn the_schema_lists_types_and_defaults() {
+        let text = "@mood:enum(calm,angry) = calm\n@gold = 5  // coins carried\n";
+        let (story, _) = load(text, "");
+
+        let schema = story.variable_schema();
+        let names: Vec<&str> = schema.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, vec!["gold", "mood"]);
+
+        assert_eq!(schema[0].kind, None);
+        assert_eq!(schema[0].default, "5");
+        assert_eq!(schema[0].description.as_deref(), Some("coins carried"));
+
+        assert_eq!(schema[1].kind.as_deref(), Some("enum"));
+        assert_eq!(
+            schema[1].allowed,
+            Some(vec![String::from("calm"), String::from("angry")])
+        );
+        assert_eq!(schema[1].default, "calm");
+    }
+
+    #[test]
+    fn story_text_and_errors_go_to_their_own_writers() {
+        let err = Transcript::default();
+        let (story, out) = renderer("");
+        let mut story = story.with_error(Box::new(err.clone()));
+        story
+            .processfile(Cursor::new(String::from(
+                "@hp = 3\nHello\n*stderr hp is @hp\nBye\n",
+            )))
+            .unwrap();
+
+        story.run().unwrap();
+        assert_eq!(out.text(), "Hello\nBye\n");
+        assert!(err.text().contains("hp is 3"));
+    }
+
+    #[test]
+    fn answers_come_from_the_given_reader() {
+        let text = "@name = \"\"\n@age = 0\n^s Your name:@name\n^i Your age:@age\nHi @name, you are @age.\n";
+        let (story, out) = renderer("");
+        let mut story = story.with_input(Box::new(Cursor::new("Ann\n41\n")));
+        story.processfile(Cursor::new(text.to_string())).unwrap();
+
+        story.run().unwrap();
+        assert!(out.text().ends_with("Hi Ann, you are 41.\n"));
+    }
+
+    #[test]
+    fn streaming_plays_a_linear_story_like_buffering() {
+        let text = "@gold = 2\n:start\nYou have @gold gold.\n?Spend:#shop\n?Keep:#end\n:shop\n@gold = @gold - 1\nNow @gold.\n:end\nBye\n";
+        let path = std::env::temp_dir().join("storyrender_linear_stream.txt");
+        std::fs::write(&path, text).unwrap();
+
+        let (mut story, streamed) = renderer("1\n");
+        let played = story.run_streaming(&path);
+        std::fs::remove_file(&path).unwrap();
+        played.unwrap();
+
+        let (mut story, buffered) = load(text, "1\n");
+        story.run().unwrap();
+        assert_eq!(streamed.text(), buffered.text());
+        assert!(buffered.text().ends_with("Now 1.\nBye\n"));
+    }
+
+    #[test]
+    fn a_stream_that_fails_stops_streaming() {
+        let path = std::env::temp_dir().join("storyrender_broken_stream.txt");
+        std::fs::write(&path, ":start\nHello\n:later\n?Go #end\n:end\n").unwrap();
+        let (mut story, out) = renderer("");
+
+        let played = story.run_streaming(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            played.unwrap_err()[..],
+            [StoryError::Malformed { line: 2, .. }]
+        ));
+        assert_eq!(out.text(), "Hello\n");
+        assert!(!story.streaming);
+
+        let path = std::env::temp_dir().join("storyrender_failing_stream.txt");
+        std::fs::write(
+            &path,
+            ":start\n@gold = 1\n:later\n!@gold > \"a\":#end\n:end\n",
+        )
+        .unwrap();
+        let (mut story, _) = renderer("");
+
+        let played = story.run_streaming(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            played.unwrap_err()[..],
+            [StoryError::TypeMismatch { .. }]
+        ));
+        assert!(!story.streaming);
+    }
+
+    #[test]
+    fn passing_a_checkpoint_saves_the_story() {
+        let path = std::env::temp_dir().join("storyrender_checkpoint.sav");
+        let text = "@gold = 3\n:*shop\n@gold = 9\n*checkpoint\nBye\n";
+        let (mut story, _) = load(text, "");
+        story.save_path = Some(path.clone());
+        story.save_interval = Duration::from_secs(60);
+        story.clock = Box::new(TestClock::default());
+
+        story.run().unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        //the *checkpoint came too soon after :*shop, so the save is still the one from the label.
+        assert!(saved.starts_with("index 1\nlabel shop\n"));
+        assert!(saved.contains("@gold = 3\n"));
+    }
+
+    #[test]
+    fn saving_leaves_other_tmp_files_alone() {
+        let dir = std::env::temp_dir().join("storyrender_save_temp");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("cave.tmp"), "a story of its own\n").unwrap();
+        let (story, _) = load("@gold = 2\n", "");
+
+        story.save_state(&dir.join("cave.save")).unwrap();
+        let other = std::fs::read_to_string(dir.join("cave.tmp")).unwrap();
+        let saved = std::fs::read_to_string(dir.join("cave.save")).unwrap();
+        let left = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(other, "a story of its own\n");
+        assert!(saved.contains("@gold = 2\n"));
+        assert_eq!(left, 2);
+    }
+
+    #[test]
+    fn a_story_including_itself_is_caught_before_reading_it_again() {
+        let dir = std::env::temp_dir().join("storyrender_self_include");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("loop.txt");
+        std::fs::write(&path, "Hello\n%include loop.txt\nBye\n").unwrap();
+
+        let (mut story, _) = renderer("");
+        story.base_dir = dir.clone();
+        story.story_path = Some(path.clone());
+        let errors = story.processfile(File::open(&path).unwrap()).unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        match &errors[..] {
+            [StoryError::IncludeCycle { line: 2, files }] => assert_eq!(files.len(), 2),
+            other => panic!("gave {:?}", other),
+        }
+        assert_eq!(story.lines.len(), 3);
+    }
+
+    #[test]
+    fn color_if_only_colors_below_the_threshold() {
+        let text = "HP [color_if @hp < 20 red]@hp[/color]\n";
+
+        for (hp, shown) in [(5.0, "HP \x1b[31m5\x1b[0m\n"), (50.0, "HP 50\n")].iter() {
+            let (mut story, out) = load(&format!("@hp = {}\n{}", hp, text), "");
+            story.color = ColorMode::Always;
+
+            story.run().unwrap();
+            assert_eq!(out.text(), *shown);
+        }
+    }
+
+    #[test]
+    fn an_if_can_assign_text_with_colons() {
+        let text = "@gold = 9\n@msg = \"\"\n@time = 0\n!@gold > 5:@msg = \"rich: very\":@msg = \"poor: very\"\n!@gold > 5:@time = 10:30\nMsg @msg\nTime @time\n";
+        let (mut story, out) = load(text, "");
+
+        story.run().unwrap();
+        assert_eq!(out.text(), "Msg \"rich: very\"\nTime 10:30\n");
+    }
+
+    #[test]
+    fn answers_past_nine_pick_their_option() {
+        let mut text = String::new();
+
+        for n in 1..=12 {
+            text.push_str(&format!("?Room {}:#room{}\n", n, n));
+        }
+
+        for n in 1..=12 {
+            text.push_str(&format!(":room{}\nIn room {}\n#end\n", n, n));
+        }
+
+        text.push_str(":end\n");
+        let (mut story, out) = load(&text, "13\n11\n");
+
+        story.run().unwrap();
+        assert!(out.text().contains("12. Room 12\n"));
+        assert!(out.text().ends_with("In room 11\n"));
+    }
+
+    #[test]
+    fn branches_to_later_labels_stream() {
+        let path = std::env::temp_dir().join("storyrender_branch_stream.txt");
+        std::fs::write(&path, ":start\nBegin\n*branch later\n:later\nLater\n").unwrap();
+        let (mut story, out) = renderer("");
+
+        let played = story.run_streaming(&path);
+        std::fs::remove_file(&path).unwrap();
+        played.unwrap();
+        assert_eq!(out.text(), "Begin\nLater\n");
+
+        let (story, _) = load("*branch nowhere\n", "");
+        assert!(matches!(
+            story.validate().unwrap_err()[..],
+            [StoryError::Malformed { line: 1, .. }]
+        ));
+    }
+
+    #[test]
+    fn seeded_branches_pick_the_same_label() {
+        let text = "*branch left right middle\n:left\nLeft\n#end\n:right\nRight\n#end\n:middle\nMiddle\n:end\n";
+        let pick = |seed: u64| {
+            let (mut story, out) = load(text, "");
+            story.rng = RefCell::new(StdRng::seed_from_u64(seed));
+            story.run().unwrap();
+            out.text()
+        };
+
+        for seed in 0..5 {
+            assert_eq!(pick(seed), pick(seed));
+        }
+    }
+
+    #[test]
+    fn heavy_branches_win_most_seeds() {
+        let text = "*branch rare*1 common*20\n:rare\nRare\n#end\n:common\nCommon\n:end\n";
+        let mut common = 0;
+
+        for seed in 0..200 {
+            let (mut story, out) = load(text, "");
+            story.rng = RefCell::new(StdRng::seed_from_u64(seed));
+            story.run().unwrap();
+
+            if out.text() == "Common\n" {
+                common += 1;
+            }
+        }
+
+        assert!(common > 150, "common won {} of 200", common);
+    }
+
+    #[test]
+    fn false_branch_conditions_rule_their_label_out() {
+        let text = "@key = 0\n*branch vault?@key==1 hall\n:vault\nVault\n#end\n:hall\nHall\n:end\n";
+
+        for seed in 0..20 {
+            let (mut story, out) = load(text, "");
+            story.rng = RefCell::new(StdRng::seed_from_u64(seed));
+            story.run().unwrap();
+            assert_eq!(out.text(), "Hall\n");
+        }
+    }
+
+    #[test]
+    fn included_chapters_keep_their_own_intro() {
+        let dir = std::env::temp_dir().join("storyrender_namespaces");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("one.txt"), ":intro\nOne begins\n#chapter2.intro\n").unwrap();
+        std::fs::write(dir.join("two.txt"), ":intro\nTwo begins\n#end\n").unwrap();
+        let text = "#chapter1.intro\n*include one.txt as chapter1\n*include two.txt as chapter2\n:end\nThe end\n";
+
+        let (mut story, out) = renderer("");
+        story.base_dir = dir.clone();
+        let loaded = story.processfile(Cursor::new(text.to_string()));
+        std::fs::remove_dir_all(&dir).unwrap();
+        loaded.unwrap();
+
+        story.run().unwrap();
+        assert_eq!(out.text(), "One begins\nTwo begins\nThe end\n");
+    }
+
+    #[test]
+    fn broken_expressions_are_found_when_loading() {
+        let text = "@gold = 5\n:shop\n@gold = (@gold + 2\n!@gold > @missing:#shop\n";
+        let (mut story, _) = renderer("");
+
+        let errors = story
+            .processfile(Cursor::new(text.to_string()))
+            .unwrap_err();
+        match &errors[..] {
+            [StoryError::Malformed {
+                line: 3, reason, ..
+            }] => {
+                assert!(reason.contains("can never be worked out"))
+            }
+            other => panic!("gave {:?}", other),
+        }
+    }
+
+    #[test]
+    fn questions_may_only_have_max_options() {
+        let text = "Pick\n?A:#end\n?B:#end\n?C:#end\n:end\n";
+
+        let (mut story, _) = renderer("");
+        story.max_options = 3;
+        assert!(story.processfile(Cursor::new(text.to_string())).is_ok());
+
+        let (mut story, _) = renderer("");
+        story.max_options = 2;
+        let errors = story
+            .processfile(Cursor::new(text.to_string()))
+            .unwrap_err();
+        assert!(matches!(
+            errors[..],
+            [StoryError::TooManyOptions {
+                line: 4,
+                first_line: 2,
+                limit: 2
+            }]
+        ));
+    }
+
+    #[test]
+    fn variables_compare_as_the_kind_they_hold() {
+        let text = "@code = \"\"\n^s Code?:@code\n!@code == \"10\":#text\nNumber\n#end\n:text\nText\n:end\n";
+        let (mut story, out) = load(text, "10\n");
+
+        story.run().unwrap();
+        assert!(out.text().ends_with("Text\n"));
+
+        let (mut story, _) = load(
+            "@code = \"\"\n^s Code?:@code\n!@code == 10:#number\n:number\n",
+            "10\n",
+        );
+        assert!(matches!(
+            story.run(),
+            Err(StoryError::TypeMismatch {
+                kinds: ("text", "number"),
+                line: 3,
+                ..
+            })
+        ));
+
+        let (mut story, _) = load("@a = \"b\"\n!@a < \"c\":#less\n:less\n", "");
+        let error = story.run().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "@a and \"c\" are both text, so < can not be used on them. Error on line 2."
+        );
+    }
+
+    #[test]
+    fn warnings_and_stderr_lines_stay_out_of_the_story() {
+        let err = Transcript::default();
+        let (story, out) = renderer("");
+        let mut story = story.with_error(Box::new(err.clone()));
+        story
+            .processfile(Cursor::new(String::from(
+                ":*start\n*stderr debug note\nStory\n",
+            )))
+            .unwrap();
+        story.save_path = Some(std::env::temp_dir().join("storyrender_no_dir/save.sav"));
+
+        story.run().unwrap();
+        assert_eq!(out.text(), "Story\n");
+        assert!(err.text().contains("checkpoint could not be saved"));
+        assert!(err.text().contains("debug note"));
+    }
+
+    #[test]
+    fn breakpoints_pause_before_their_line() {
+        let text = "@gold = 5\nOne\n@gold = 7\nTwo\nThree\n";
+        let (mut story, out) = load(text, "");
+        story.set_breakpoint(3);
+        story.set_breakpoint(5);
+
+        assert!(matches!(story.run().unwrap(), StepResult::Breakpoint(3)));
+        assert_eq!(out.text(), "One\n");
+        assert_eq!(story.variable("gold"), Some(&Value::Num(5.0)));
+
+        story.clear_breakpoint(5);
+        assert!(matches!(story.run().unwrap(), StepResult::Finished));
+        assert_eq!(out.text(), "One\nTwo\nThree\n");
+    }
+
+    #[test]
+    fn validate_lists_every_missing_label() {
+        let text = ":start\n#strat\n?Go:#hall\n?Stay:#start\n@gold = 1\n!@gold > 0:#vault:#start\n#@next\n";
+        let (story, _) = load(text, "");
+
+        let lines: Vec<Option<usize>> = story
+            .validate()
+            .unwrap_err()
+            .iter()
+            .map(StoryError::line)
+            .collect();
+        assert_eq!(lines, vec![Some(2), Some(3), Some(6)]);
+
+        let (story, _) = load(":start\n?Again:#start\n", "");
+        assert!(story.validate().is_ok());
+    }
+
+    #[test]
+    fn maybe_assign_only_fills_empty_variables() {
+        let text = "@name = \"\"\n@title = Sir\n@name ?= Hero\n@title ?= Lord\n@title @name\n";
+        let (mut story, out) = load(text, "");
+
+        story.run().unwrap();
+        assert_eq!(out.text(), "Sir Hero\n");
+    }
+
+    #[test]
+    fn step_limits_stop_endless_loops() {
+        let text = ":top\nAgain\n#top\n";
+        let (mut story, _) = load(text, "");
+        story.max_steps = Some(10);
+
+        assert!(matches!(
+            story.run(),
+            Err(StoryError::StepLimitExceeded { limit: 10, .. })
+        ));
+
+        let (mut story, _) = load("One\nTwo\n", "");
+        story.max_steps = Some(10);
+        assert!(matches!(story.run().unwrap(), StepResult::Finished));
+    }
+
+    #[test]
+    fn a_channel_front_end_drives_a_branching_story() {
+        let (events, shown) = std::sync::mpsc::channel();
+        let (answer, answers) = std::sync::mpsc::channel();
+        let text = "Pick a path\n?Left:#left\n?Right:#right\n:left\nWent left\n#end\n:right\nWent right\n:end\n";
+        let mut story = Renderer::new().with_io(Box::new(ChannelIo { events, answers }));
+        story.color = ColorMode::Never;
+        story.processfile(Cursor::new(text.to_string())).unwrap();
+
+        answer.send(String::from("2")).unwrap();
+        story.run().unwrap();
+        drop(story);
+
+        let shown: Vec<OutputEvent> = shown.iter().collect();
+        assert_eq!(
+            shown[0],
+            OutputEvent::Narrative(String::from("Pick a path"))
+        );
+        assert!(shown.iter().any(
+            |event| matches!(event, OutputEvent::MenuOption { n: 2, text, .. } if text == "Right")
+        ));
+        assert_eq!(
+            shown.last(),
+            Some(&OutputEvent::Narrative(String::from("Went right")))
+        );
+    }
+
+    #[test]
+    fn rand_works_in_conditions() {
+        let text =
+            "!rand(1, 6) > 6:#never\n!rand(1, 6) >= 1:#rolled\n:never\nNever\n:rolled\nRolled\n";
+        let (story, out) = renderer("");
+        let mut story = story.with_seed(3);
+        story.processfile(Cursor::new(text.to_string())).unwrap();
+
+        story.run().unwrap();
+        assert_eq!(out.text(), "Rolled\n");
+    }
+
+    #[test]
+    fn dying_goes_back_to_the_last_checkpoint() {
+        let text = "@tries = 0\n:*camp\nAt camp\n@tries = @tries + 1\n?Fight:#dead\n?Rest:#end\n:dead\nNever shown\n:end\nBye\n";
+        let (mut story, out) = load(text, "1\n2\n");
+        story.death_label = Some(String::from("dead"));
+
+        story.run().unwrap();
+        assert_eq!(out.text().matches("At camp").count(), 2);
+        assert!(!out.text().contains("Never shown"));
+        assert!(out.text().ends_with("Bye\n"));
+        //the try before dying was undone with the rest of what happened after the checkpoint.
+        assert_eq!(story.variable("tries"), Some(&Value::Num(1.0)));
+    }
+
+    #[test]
+    fn chances_follow_the_seed() {
+        //each roll is a bit of @hits, so the whole sequence is kept in one number.
+        let text = format!(
+            "@hits = 0\n{}",
+            "!chance(50):@hits = @hits * 2 + 1:@hits = @hits * 2\n".repeat(8)
+        );
+        let rolls = || {
+            let (story, _) = renderer("");
+            let mut story = story.with_seed(11);
+            story.processfile(Cursor::new(text.clone())).unwrap();
+            story.run().unwrap();
+            story.variable("hits").and_then(Value::as_number).unwrap()
+        };
+
+        let hits = rolls();
+        assert_eq!(hits, rolls());
+        assert!((0.0..256.0).contains(&hits));
+
+        let (story, _) = load("", "");
+        assert_eq!(story.eval_expression("chance(0)").unwrap(), Value::Num(0.0));
+        assert_eq!(
+            story.eval_expression("chance(100)").unwrap(),
+            Value::Num(1.0)
+        );
+        assert!(story.eval_expression("chance(150)").is_err());
+    }
+
+    #[test]
+    fn escaped_at_signs_print_as_they_are() {
+        let text = "@user = ann\n\\@home is where you start\nMail me at ann\\@example.com\nWrite to @user\\@example.com\n";
+        let (mut story, out) = load(text, "");
+
+        story.run().unwrap();
+        assert_eq!(
+            out.text(),
+            "@home is where you start\nMail me at ann@example.com\nWrite to ann@example.com\n"
+        );
+    }
+
+    #[test]
+    fn endings_flag_the_ones_that_can_not_be_reached() {
+        let text = ":start\n?Win:#win\n?Leave:#bye\n:win\nYou win\n*ending\n#bye\n:secret\nSecret\n*ending\n#bye\n:bye\nBye\n";
+        let (story, _) = load(text, "");
+
+        assert_eq!(
+            story.endings(),
+            vec![
+                (String::from("win"), true),
+                (String::from("secret"), false),
+                (String::from("bye"), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn wait_reads_one_line_before_going_on() {
+        let text = "Before\n*wait\n*wait Hit Enter for more\nAfter\n";
+        let (mut story, out) = load(text, "first\nsecond\nleft over\n");
+
+        story.run().unwrap();
+        assert!(out.text().starts_with("Before\n"));
+        assert!(out.text().contains("Press Enter to Continue."));
+        assert!(out.text().contains("Hit Enter for more"));
+        assert!(out.text().ends_with("After\n"));
+        assert_eq!(story.io.read_line().as_deref(), Some("left over"));
+    }
+
+    #[test]
+    fn preformatted_lines_are_kept_exactly() {
+        let art = "  /\\_/\\\n\n ( o.o )  é\n—  > ^ <\n*not a directive";
+        let text = format!("*preformatted\n{}\n*endpre\nDone\n", art);
+        let (mut story, out) = load(&text, "");
+
+        story.run().unwrap();
+        assert_eq!(out.text(), format!("{}\nDone\n", art));
+    }
+
+    #[test]
+    fn function_arguments_are_split_before_variables_are_filled_in() {
+        let text = "@name = \"\"\n^s Name?:@name\n@size = len(@name)\n@loud = upper(@name)\n@first = substr(@name, 0, 3)\n";
+
+        for (answer, size, loud, first) in [
+            ("Ann, B", 6.0, "ANN, B", "Ann"),
+            ("a(b\"c)", 6.0, "A(B\"C)", "a(b"),
+        ]
+        .iter()
+        {
+            let (mut story, _) = load(text, &format!("{}\n", answer));
+
+            story.run().unwrap();
+            assert_eq!(story.variable("size"), Some(&Value::Num(*size)));
+            assert_eq!(story.variable("loud"), Some(&Value::Str(loud.to_string())));
+            assert_eq!(
+                story.variable("first"),
+                Some(&Value::Str(first.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn function_arguments_keep_their_spaces() {
+        let text = "@name = \"\"\n^s Name?:@name\n@size = len(@name)\n@trimmed = trim(@name)\n@inner = len(upper(@name))\n@quoted = len(\"  hi \")\n";
+        let (mut story, _) = renderer("  Ann  \n");
+        story.trim_input = false;
+        story.processfile(Cursor::new(text.to_string())).unwrap();
+
+        story.run().unwrap();
+        assert_eq!(story.variable("size"), Some(&Value::Num(7.0)));
+        assert_eq!(
+            story.variable("trimmed"),
+            Some(&Value::Str(String::from("Ann")))
+        );
+        assert_eq!(story.variable("inner"), Some(&Value::Num(7.0)));
+        assert_eq!(story.variable("quoted"), Some(&Value::Num(5.0)));
+    }
+
+    #[test]
+    fn expressions_are_worked_out_against_the_variables() {
+        let (mut story, out) = load("@gold = 5\n@name = Ann\nNever run\n", "");
+        story.set_variable("gold", Value::Num(20.0));
+
+        assert_eq!(
+            story.eval_expression("@gold * 2 + 1").unwrap(),
+            Value::Num(41.0)
+        );
+        assert_eq!(
+            story.eval_expression("upper(@name)").unwrap(),
+            Value::Str(String::from("ANN"))
+        );
+        assert!(story.eval_expression("@nobody + 1").is_err());
+        assert_eq!(out.text(), "");
+    }
+
+    #[test]
+    fn evaluating_leaves_the_story_rolls_alone() {
+        let text = "@roll = rand(1, 1000000)\n";
+        let (story, _) = renderer("");
+        let mut story = story.with_seed(5);
+        story.processfile(Cursor::new(text.to_string())).unwrap();
+
+        let shared = &story;
+        let first = shared.eval_expression("rand(1, 1000000)").unwrap();
+        assert_eq!(shared.eval_expression("rand(1, 1000000)").unwrap(), first);
+
+        story.run().unwrap();
+        assert_eq!(story.variable("roll"), Some(&first));
+    }
+
+    #[test]
+    fn repeat_runs_its_body_that_many_times() {
+        let (mut story, out) = load("~repeat 3\nKnock\n~endrepeat\nOpen\n", "");
+        story.run().unwrap();
+        assert_eq!(out.text(), "Knock\nKnock\nKnock\nOpen\n");
+
+        let text = "@waves = 2\n@count = 0\n~repeat @waves\n@count = @count + 1\nWave @count\n~endrepeat\n";
+        let (mut story, out) = load(text, "");
+        story.run().unwrap();
+        assert_eq!(out.text(), "Wave 1\nWave 2\n");
+
+        let (mut story, _) = load("~repeat -1\nNever\n~endrepeat\n", "");
+        assert!(matches!(
+            story.run(),
+            Err(StoryError::Malformed { line: 1, .. })
+        ));
+
+        let (mut story, _) = load("~repeat 5\nToo many\n~endrepeat\n", "");
+        story.loop_limit = 4;
+        assert!(matches!(
+            story.run(),
+            Err(StoryError::LoopLimit { limit: 4, .. })
+        ));
+    }
+
+    //knows only whole numbers and the word seven, and keeps every expression it was asked about.
+    struct MockEngine(Rc<RefCell<Vec<String>>>);
+
+    impl ExpressionEngine for MockEngine {
+        fn eval(&self, expr: &str, _vars: &VarView) -> Result<Value, ExprError> {
+            let expr = expr.trim();
+            self.0.borrow_mut().push(expr.to_string());
+
+            match expr {
+                "seven" => Ok(Value::Num(7.0)),
+                _ => expr
+                    .parse()
+                    .map(Value::Num)
+                    .map_err(|_| ExprError(format!("{} is not known", expr))),
+            }
+        }
+    }
+
+    #[test]
+    fn assignments_conditions_and_checks_use_the_engine() {
+        let asked = Rc::new(RefCell::new(Vec::new()));
+        let text = "@x = seven\n!@x == 7:#yes\nNo\n#end\n:yes\nYes\n:end\n";
+        let (mut story, out) = renderer("");
+        story.expressions = Box::new(MockEngine(asked.clone()));
+        story.processfile(Cursor::new(text.to_string())).unwrap();
+
+        story.run().unwrap();
+        assert_eq!(out.text(), "Yes\n");
+        assert_eq!(story.variable("x"), Some(&Value::Num(7.0)));
+        assert!(asked.borrow().contains(&String::from("seven")));
+
+        //the mock has no sums, so the load time check finds this one can never be worked out.
+        let (mut story, _) = renderer("");
+        story.expressions = Box::new(MockEngine(asked.clone()));
+        let errors = story
+            .processfile(Cursor::new(String::from("@y = 1 + 2\n")))
+            .unwrap_err();
+        assert!(matches!(
+            errors[..],
+            [StoryError::Malformed { line: 1, .. }]
+        ));
+        assert!(asked.borrow().contains(&String::from("1 + 2")));
+    }
+
+    #[test]
+    fn broken_questions_are_errors_not_panics() {
+        let (mut story, _) = renderer("");
+        let errors = story
+            .processfile(Cursor::new(String::from("Pick\n?Left #left\n:left\n")))
+            .unwrap_err();
+        assert!(matches!(
+            errors[..],
+            [StoryError::Malformed { line: 2, .. }]
+        ));
+
+        let (mut story, _) = load("Pick\n?Left:#left\n?Right:#rihgt\n:left\n", "2\n");
+        match story.run() {
+            Err(StoryError::Malformed {
+                line: 3, reason, ..
+            }) => {
+                assert_eq!(reason, "there is no label :rihgt")
+            }
+            other => panic!("gave {:?}", other),
+        }
+    }
+
+    #[test]
+    fn broken_input_lines_are_errors_not_panics() {
+        let cases = [
+            ("^:@name", "missing its type"),
+            ("^x Name:@name", "^x is not a type of input"),
+            ("^s Name @name", "should look like ^i prompt:@variable"),
+            ("^s Name:name", "must be stored in a @variable"),
+        ];
+
+        for (line, expected) in cases.iter() {
+            let (mut story, _) = renderer("");
+            let text = format!("@name = 0\n{}\n", line);
+            let errors = story.processfile(Cursor::new(text)).unwrap_err();
+
+            match &errors[..] {
+                [StoryError::Malformed {
+                    line: 2, reason, ..
+                }] => {
+                    assert!(reason.contains(expected), "{} gave {}", line, reason)
+                }
+                other => panic!("{} gave {:?}", line, other),
+            }
+        }
+    }
+
+    #[test]
+    fn yes_or_no_asks_until_it_gets_one() {
+        let text = "@agree = false\n^y Open the door?:@agree\n!@agree:#inside\nOutside\n#end\n:inside\nInside\n:end\n";
+        let (mut story, out) = load(text, "maybe\nYES\n");
+
+        story.run().unwrap();
+        assert_eq!(story.variable("agree"), Some(&Value::Bool(true)));
+        assert!(out.text().contains("Answer y or yes, or n or no."));
+        assert!(out.text().ends_with("Inside\n"));
+
+        let (mut story, _) = load(text, "n\n");
+        story.run().unwrap();
+        assert_eq!(story.variable("agree"), Some(&Value::Bool(false)));
+    }
+
+    #[test]
+    fn numbers_are_only_close_enough_when_asked() {
+        let text = "!0.1 + 0.2 == 0.3:#equal\nApart\n#end\n:equal\nEqual\n:end\n";
+
+        for (epsilon, shown) in [(0.0, "Apart\n"), (1e-9, "Equal\n")].iter() {
+            let (mut story, out) = load(text, "");
+            story.epsilon = *epsilon;
+
+            story.run().unwrap();
+            assert_eq!(out.text(), *shown);
+        }
+
+        let (story, _) = load("", "");
+        let close = story.eval_expression("approx(0.1 + 0.2, 0.3, 0.001)");
+        assert_eq!(close.unwrap(), Value::Num(1.0));
+        let apart = story.eval_expression("approx(1, 1.5, 0.1)");
+        assert_eq!(apart.unwrap(), Value::Num(0.0));
+    }
+
+    //a stream that fails every read and write, like a closed pipe.
+    struct Broken;
+
+    impl io::Read for Broken {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "not UTF-8"))
+        }
+    }
+
+    impl Write for Broken {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+        }
+    }
+
+    #[test]
+    fn broken_streams_end_the_story_without_panicking() {
+        let mut story = Renderer::new()
+            .with_input(Box::new(BufReader::new(Broken)))
+            .with_output(Box::new(Broken))
+            .with_error(Box::new(Broken));
+        story.color = ColorMode::Never;
+        story.width_query = || None;
+        story
+            .processfile(Cursor::new(String::from("Hello\n? Go:#end\n:end\n")))
+            .unwrap();
+
+        assert!(matches!(
+            story.run(),
+            Err(StoryError::UnexpectedEof { line: 2 })
+        ));
+    }
+
+    #[test]
+    fn a_story_that_is_not_utf8_is_an_error() {
+        let (mut story, _) = renderer("");
+        let errors = story
+            .processfile(Cursor::new(b"Hello\n\xff\xfe\n".to_vec()))
+            .unwrap_err();
+
+        assert!(matches!(
+            errors[..],
+            [StoryError::Malformed { line: 2, .. }]
+        ));
+    }
+
+    #[test]
+    fn running_out_of_answers_is_an_error() {
+        let text = "@name = \"\"\nHello\n^s Name?:@name\n";
+        let (mut story, _) = load(text, "");
+        assert!(matches!(
+            story.run(),
+            Err(StoryError::UnexpectedEof { line: 3 })
+        ));
+
+        let (mut story, _) = load("Pick\n?Left:#end\n?Right:#end\n:end\n", "");
+        assert!(matches!(
+            story.run(),
+            Err(StoryError::UnexpectedEof { line: 2 })
+        ));
+    }
+}
@@ -1,461 +1,450 @@
-use float_cmp::ApproxEq;
-use nom::bytes::complete::{is_not, tag, take_until};
-use nom::{multi::*, sequence::*};
-use regex::Regex;
-use std::collections::HashMap;
-use std::io::Write;
 use std::io::{BufRead, BufReader};
-use std::{env, fs::File, io, path::Path, str::FromStr};
-
-#[derive(Debug)]
-struct Renderer {
-    pub lines: Vec<String>,
-    pub variables: HashMap<String, String>,
-    pub labels: HashMap<String, usize>,
-    pub index: usize,
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, fs::File, str::FromStr};
+use storyrender::{Renderer, StoryError, Value};
+
+const USAGE: &str = "usage: storyrender <story file> [options]
+
+options:
+  --advance-to label     run silently, answering inputs, until label is reached
+  --pause-on-finish      wait for Enter once the story ends
+  --ignore-label-case    let #Intro find :intro
+  --lenient              conditions treat variables that were never created as 0
+  --trim-input           take spaces off both ends of answers
+  --strict-numbers       comparing text that is not a number is an error
+  --epsilon n            numbers this close are equal in == and !=, 0 if not given
+  --strict-flow          refuse #@variable gotos
+  --seed n               seed rand and randf so every run rolls the same
+  --timeout secs         stop the story if it runs longer than this
+  --max-steps n          stop the story once it has run this many lines
+  --var name=value       set a variable before the story loads
+  --input-source path    read answers from a named pipe or socket instead of the keyboard
+  --entry path           load the story from this file inside a .zip or .tar.gz
+  --death-label label    going to this label returns to the last checkpoint instead
+  --death-prompt text    shown before going back, waiting for Enter
+  --save file            save checkpoints to this file, --continue alone uses the story's name ending in .save
+  --continue             start from the last checkpoint saved, if there is one
+  --stream               play the story while reading it, for huge stories that only move forward
+  --dump                 list the variables once the story ends
+  --lint                 check the story for problems without playing it, printing OK if there are none
+  --schema               print the story's variables as JSON and exit
+  --result file          write the score, answers and ending as JSON, - for the screen
+  --preview label        print the text after a label and exit
+  --edges label          list where a label's block can lead and exit
+  --endings              list the story's endings, and which can not be reached, and exit
+  --help                 show this message";
+
+//what was asked for on the command line.
+#[derive(Debug, Default)]
+struct Options {
+    path: String,
+    help: bool,
+    advance_to: Option<String>,
+    pause_on_finish: bool,
+    ignore_label_case: bool,
+    lenient: bool,
+    trim_input: bool,
+    strict_numbers: bool,
+    epsilon: Option<f64>,
+    strict_flow: bool,
+    seed: Option<u64>,
+    timeout: Option<Duration>,
+    max_steps: Option<usize>,
+    vars: Vec<(String, String)>,
+    input_source: Option<String>,
+    entry: Option<String>,
+    save: Option<String>,
+    death_label: Option<String>,
+    death_prompt: Option<String>,
+    resume: bool,
+    stream: bool,
+    dump: bool,
+    lint: bool,
+    schema: bool,
+    result: Option<String>,
+    preview: Option<String>,
+    edges: Option<String>,
+    endings: bool,
 }
 
-impl Renderer {
-    fn new() -> Renderer {
-        Renderer {
-            lines: Vec::new(),
-            variables: HashMap::new(),
-            labels: HashMap::new(),
-            index: 0,
+//args without the program name, an Err holds what was wrong with them.
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut path = None;
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        let mut value = |what: &str| match iter.next() {
+            Some(v) => Ok(v.clone()),
+            None => Err(format!("{} requires {}", arg, what)),
+        };
+
+        match &arg[..] {
+            "--help" | "-h" => options.help = true,
+            "--advance-to" => options.advance_to = Some(value("a label name")?),
+            "--pause-on-finish" => options.pause_on_finish = true,
+            "--ignore-label-case" => options.ignore_label_case = true,
+            "--lenient" => options.lenient = true,
+            "--trim-input" => options.trim_input = true,
+            "--strict-numbers" => options.strict_numbers = true,
+            "--epsilon" => match f64::from_str(&value("a number")?) {
+                Ok(epsilon) if epsilon >= 0.0 => options.epsilon = Some(epsilon),
+                _ => return Err(String::from("--epsilon requires a number of 0 or more")),
+            },
+            "--strict-flow" => options.strict_flow = true,
+            "--seed" => match u64::from_str(&value("a whole number")?) {
+                Ok(seed) => options.seed = Some(seed),
+                Err(_) => return Err(String::from("--seed requires a whole number")),
+            },
+            "--timeout" => match f64::from_str(&value("a number of seconds")?) {
+                Ok(secs) if secs >= 0.0 => options.timeout = Some(Duration::from_secs_f64(secs)),
+                _ => return Err(String::from("--timeout requires a number of seconds")),
+            },
+            "--max-steps" => match usize::from_str(&value("a whole number")?) {
+                Ok(steps) => options.max_steps = Some(steps),
+                Err(_) => return Err(String::from("--max-steps requires a whole number")),
+            },
+            "--var" => match value("name=value")?.split_once('=') {
+                Some((name, v)) => {
+                    let name = name.trim().trim_start_matches('@');
+                    options.vars.push((name.to_string(), v.to_string()));
+                }
+                None => return Err(String::from("--var requires name=value")),
+            },
+            "--input-source" => options.input_source = Some(value("a pipe or socket path")?),
+            "--entry" => options.entry = Some(value("the story's path inside the archive")?),
+            "--death-label" => options.death_label = Some(value("a label name")?),
+            "--death-prompt" => options.death_prompt = Some(value("the text to show")?),
+            "--save" => options.save = Some(value("a file name")?),
+            "--continue" => options.resume = true,
+            "--stream" => options.stream = true,
+            "--dump" => options.dump = true,
+            "--lint" => options.lint = true,
+            "--schema" => options.schema = true,
+            "--result" => options.result = Some(value("a file name, or - for the screen")?),
+            "--preview" => options.preview = Some(value("a label name")?),
+            "--edges" => options.edges = Some(value("a label name")?),
+            "--endings" => options.endings = true,
+            _ if arg.starts_with("--") => return Err(format!("{} is not a known option", arg)),
+            _ => path = Some(arg.clone()),
         }
     }
 
-    fn processfile(&mut self, file: File) {
-        let reader = BufReader::new(file);
+    match path {
+        Some(p) => options.path = p,
+        None if options.help => {}
+        None => return Err(String::from("no story file was given")),
+    }
 
-        for (index, curline) in reader.lines().enumerate() {
-            let text = curline.unwrap();
-            self.lines.push(text.clone());
+    //these need the whole story loaded before it plays.
+    if options.stream {
+        let needs_loading = [
+            ("--advance-to", options.advance_to.is_some()),
+            ("--timeout", options.timeout.is_some()),
+            ("--entry", options.entry.is_some()),
+            ("--schema", options.schema),
+            ("--preview", options.preview.is_some()),
+            ("--edges", options.edges.is_some()),
+            ("--endings", options.endings),
+            ("--lint", options.lint),
+            ("--continue", options.resume),
+            ("--death-label", options.death_label.is_some()),
+        ];
+
+        if let Some((flag, _)) = needs_loading.iter().find(|(_, set)| *set) {
+            return Err(format!("--stream can not be used with {}", flag));
+        }
+    }
 
-            if text.is_empty() {
-                continue;
-            }
+    Ok(options)
+}
 
-            match &text[0..1] {
-                ":" => {
-                    self.labels.insert(text[1..].to_string(), index);
-                }
-                "@" => {
-                    match self.tokenize(self.lines[index].clone(), "=") {
-                        Ok((l, _)) => self.variables.insert(l[1..].to_string(), String::from("0")),
-                        Err(_) => continue,
-                    };
-                }
-                _ => continue,
-            }
+//checkpoints are only saved when --save or --continue asks for them.
+fn save_path(options: &Options, story: &Path) -> Option<PathBuf> {
+    match &options.save {
+        Some(save) => Some(PathBuf::from(save)),
+        None if options.resume => Some(story.with_extension("save")),
+        None => None,
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            std::process::exit(2);
         }
+    };
+
+    if options.help {
+        println!("{}", USAGE);
+        return;
     }
 
-    fn process_variables(&self, text: &str) -> String {
-        let mut s = String::from(text);
-
-        for item in parse_variables(text).iter() {
-            if !text.is_empty() {
-                let var = match self.variables.get(&item[..]) {
-                    Some(v) => v,
-                    None => panic!(
-                        "Variable Missing at line {}. It must be created before the block using it.",
-                        self.index
-                    ),
-                };
-                s = s.replace(&format!("@{}", &item[..]), var);
+    let mut story = match &options.input_source {
+        Some(source) => match input_source(source) {
+            Ok(input) => Renderer::new().with_input(input),
+            Err(why) => {
+                eprintln!("Could not open {}, {}.", source, why);
+                std::process::exit(1);
             }
-        }
-        s
+        },
+        None => Renderer::new(),
+    };
+    story.advance_to = options.advance_to.clone();
+    story.pause_on_finish = options.pause_on_finish;
+    story.case_insensitive_labels = options.ignore_label_case;
+    story.lenient = options.lenient;
+    story.trim_input = options.trim_input;
+    story.strict_numbers = options.strict_numbers;
+    story.strict_flow = options.strict_flow;
+    story.max_steps = options.max_steps;
+    story.death_label = options.death_label.clone();
+    story.death_prompt = options.death_prompt.clone();
+
+    if let Some(epsilon) = options.epsilon {
+        story.epsilon = epsilon;
     }
 
-    fn process_expression(&self, text: String) -> bool {
-        let (left, mid, right) = self.get_expression(text);
-        let mut isnan = false;
+    if let Some(seed) = options.seed {
+        story = story.with_seed(seed);
+    }
 
-        let lvalue: f64 = match tinyexpr::interp(&left[..]) {
-            Ok(v) => v as f64,
-            Err(_) => {
-                isnan = true;
-                0.0_f64
-            }
-        };
+    for (name, value) in &options.vars {
+        story.set_variable(name, Value::infer(value));
+    }
 
-        let rvalue: f64 = match tinyexpr::interp(&right[..]) {
-            Ok(v) => v as f64,
-            Err(_) => {
-                isnan = true;
-                0.0_f64
+    let path = Path::new(&options.path);
+    let display = path.display();
+    story.base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    story.story_path = Some(path.to_path_buf());
+    story.save_path = save_path(&options, path);
+
+    if options.stream {
+        if let Err(errors) = story.run_streaming(path) {
+            for e in &errors {
+                eprintln!("{}", e);
             }
-        };
 
-        match &mid[..] {
-            "==" => {
-                if isnan {
-                    left == right
-                } else {
-                    lvalue.approx_eq(
-                        rvalue,
-                        float_cmp::F64Margin {
-                            ulps: 16,
-                            epsilon: 0.0,
-                        },
-                    )
-                }
-            }
-            "!=" => {
-                if isnan {
-                    left != right
-                } else {
-                    !lvalue.approx_eq(
-                        rvalue,
-                        float_cmp::F64Margin {
-                            ulps: 16,
-                            epsilon: 0.0,
-                        },
-                    )
-                }
-            }
-            "<=" => {
-                if isnan {
-                    panic!("strings cant be compared with <=, line {}", self.index + 1)
-                } else {
-                    lvalue <= rvalue
-                }
-            }
-            ">=" => {
-                if isnan {
-                    panic!("strings cant be compared with >=, line {}", self.index + 1)
-                } else {
-                    lvalue >= rvalue
-                }
-            }
-            "<" => {
-                if isnan {
-                    panic!("strings cant be compared with <, line {}", self.index + 1)
-                } else {
-                    lvalue < rvalue
-                }
-            }
-            ">" => {
-                if isnan {
-                    panic!("strings cant be compared with >, line {}", self.index + 1)
-                } else {
-                    lvalue > rvalue
-                }
-            }
-            _ => panic!("No expression pattern found. line {}", self.index + 1),
+            std::process::exit(1);
         }
-    }
 
-    fn get_expression(&self, text: String) -> (String, String, String) {
-        let re = Regex::new(r"!=|==|<=|>=|<|>").unwrap();
-        let mut mid = String::new();
+        finish(&story, &options);
+        return;
+    }
 
-        let mut part = re.captures_iter(&text[..]);
-        mid.push_str(&part.next().unwrap()[0]);
+    let loaded = match &options.entry {
+        Some(entry) => story.load_from_archive(path, entry),
+        None => match File::open(&path) {
+            Err(why) => {
+                eprintln!("Could not open {}, {}.", display, why);
+                std::process::exit(1);
+            }
+            Ok(file) => story.processfile(file),
+        },
+    };
+    //labels from every include are known by now, so gotos can be checked against them.
+    let unknown_targets = story.validate().err().unwrap_or_default();
 
-        let arr: Vec<&str> = text.split(&mid[..]).collect();
+    if options.lint {
+        let mut errors = loaded.err().unwrap_or_default();
+        errors.extend(unknown_targets);
+        errors.extend(story.missing_variables());
 
-        if arr.len() != 2 {
-            panic!(
-                "Expressions must containa a left side, right side and a operator. Line {}",
-                self.index
-            );
+        for e in &errors {
+            eprintln!("{}", story.explain(e));
         }
 
-        (String::from(arr[0]), mid, String::from(arr[1]))
-    }
-
-    fn tokenize(&self, line: String, pat: &str) -> Result<(String, String), String> {
-        let arr: Vec<&str> = line.split(pat).collect();
-
-        if arr.len() != 2 {
-            return Err(format!(
-                "The Token {} contained {} but should have only 2 at line {}.
-            It should be seperated by {}",
-                line,
-                arr.len(),
-                self.index,
-                pat,
-            ));
+        match errors.is_empty() {
+            true => println!("OK"),
+            false => std::process::exit(1),
         }
 
-        let mut iter = arr.iter();
-        Ok((
-            String::from(*iter.next().expect("expected 2 names, got 0")),
-            String::from(*iter.next().expect("expected 2 names, got 1")),
-        ))
+        return;
     }
 
-    fn iftokenize(
-        &self,
-        line: String,
-        pat: &str,
-    ) -> Result<(usize, String, String, String), String> {
-        let arr: Vec<&str> = line.split(pat).collect();
-
-        if arr.len() < 2 || arr.len() > 3 {
-            return Err(format!(
-                "The Token {} contained {} but should have 2 or 3 parts at line {}.
-            It should be seperated by {}",
-                line,
-                arr.len(),
-                self.index + 1,
-                pat,
-            ));
+    if let Err(errors) = loaded {
+        for e in &errors {
+            eprintln!("{}", story.explain(e));
         }
 
-        let mut iter = arr.iter();
-        Ok((
-            arr.len(),
-            String::from(*iter.next().expect("expected 2 names, got 0")),
-            String::from(*iter.next().expect("expected 2 names, got 1")),
-            String::from(*iter.next().unwrap_or(&"")),
-        ))
-    }
-
-    fn input_wait(&mut self) {
-        println!("\nPress Enter to Continue.");
-        read_line();
-        self.clear_screen();
+        if errors.iter().any(StoryError::is_fatal) {
+            std::process::exit(1);
+        }
     }
 
-    fn clear_screen(&mut self) {
-        clear();
-        self.index += 1;
+    //a goto to a missing label only fails once it is reached, so the story still plays.
+    for e in &unknown_targets {
+        eprintln!("Warning: {}", story.explain(e));
     }
 
-    fn printmove(&mut self, s: &str) {
-        println!("{}", self.process_variables(s));
-        self.index += 1;
+    if options.schema {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&story.variable_schema()).unwrap()
+        );
+        return;
     }
 
-    fn process_input(&mut self) {
-        let (left, right) = self.tokenize(self.lines[self.index].clone(), ":").unwrap();
-        let mut ret;
-
-        if !self.variables.contains_key(&right[1..]) {
-            panic!(
-                "A Variable must be initalized outside of a Input statement before it can be used.
-            The Variable {} on line {} is not Initalized yet.",
-                &right[1..],
-                self.index + 1
-            );
+    if let Some(label) = &options.edges {
+        for (choice, target) in story.outgoing_edges(label) {
+            println!("{} -> #{}", choice, target);
         }
 
-        match &left[1..2] {
-            "i" => loop {
-                println!("\n{}", &left[2..]);
-
-                ret = read_line();
+        return;
+    }
 
-                if ret.chars().any(char::is_alphabetic) {
-                    println!("You may only enter in a Number. Please try again.");
-                    continue;
-                } else {
-                    break;
-                }
-            },
-            "s" => {
-                println!("\n{}", &left[2..]);
-                ret = read_line();
+    if options.endings {
+        for (label, reachable) in story.endings() {
+            match reachable {
+                true => println!(":{}", label),
+                false => println!(":{} can not be reached", label),
             }
-            _ => panic!(
-                "Missing a i or s for input type at line {}. Example: ^i hows many?",
-                self.index + 1
-            ),
         }
 
-        *self.variables.get_mut(&right[1..]).unwrap() = ret;
-        self.index += 1;
+        return;
     }
 
-    fn process_questions(&mut self) {
-        let mut gotos: Vec<String> = Vec::new();
-        let mut q = 0;
-
-        while !self.lines[self.index].is_empty() && &self.lines[self.index][0..1] == "?" {
-            let (left, right) = self.tokenize(self.lines[self.index].clone(), ":").unwrap();
-            gotos.push(right.replace("#", ""));
-            println!("{}. {}", q + 1, &left[1..]);
-            q += 1;
-            self.index += 1;
-        }
-
-        let mut input: usize = 0;
-        let mut ret;
-
-        while input < 1 || input > q {
-            println!("Enter a number from 1 to {}", q);
-            ret = read_line();
-
-            if ret.chars().any(char::is_alphabetic) || ret.is_empty() {
-                println!("You must use a number");
-                continue;
+    if let Some(label) = &options.preview {
+        match story.render_label(label) {
+            Ok(text) => println!("{}", text),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
             }
-
-            input = match i32::from_str(&ret[..]) {
-                Ok(i) => i as usize,
-                Err(_) => {
-                    println!("You must use a number");
-                    continue;
-                }
-            };
         }
 
-        let label = gotos.get(input - 1).unwrap();
-        match self.labels.get(label) {
-            Some(v) => self.index = *v,
-            None => {
-                panic!(
-                    "Goto {} Missing. Found on Question near line {}.",
-                    label, self.index
-                );
-            }
-        };
+        return;
     }
 
-    fn process_variable(&mut self, opt: Option<String>) {
-        let text = match &opt {
-            None => self.lines[self.index].clone(),
-            Some(s) => s.clone(),
-        };
+    if let Some(label) = &story.advance_to {
+        if story.label_index(label).is_none() {
+            eprintln!("--advance-to label {} does not exist in {}", label, display);
+            std::process::exit(2);
+        }
+    }
 
-        match self.tokenize(text, "=") {
-            Ok((l, r)) => {
-                let p = self.process_variables(&r);
-                match tinyexpr::interp(&p[..]) {
-                    //update as variable
-                    Ok(v) => *self.variables.get_mut(&l[1..]).unwrap() = v.to_string(),
-                    //no calulations done becuase its a string so process as string.
-                    Err(_) => *self.variables.get_mut(&l[1..]).unwrap() = p.clone(),
-                };
-
-                self.index += 1;
-            }
-            Err(_) => match &opt {
-                None => self.printmove(&self.lines[self.index].clone()),
-                Some(_) => panic!(
-                    "A Variable must be initalized before it can be used. Error on line {}.",
-                    self.index + 1
-                ),
-            },
-        };
+    if let Some(save) = story
+        .save_path
+        .clone()
+        .filter(|save| options.resume && save.exists())
+    {
+        if let Err(e) = story.load_state(&save) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
     }
 
-    fn process_goto(&mut self, opt: Option<String>) {
-        let text = match opt {
-            None => self.lines[self.index].clone(),
-            Some(s) => s,
-        };
+    let result = match options.timeout {
+        Some(limit) => story.run_with_timeout(limit),
+        None => story.run(),
+    };
 
-        let label = text.replace("#", "").replace(":", "");
-        match self.labels.get(&label) {
-            Some(v) => self.index = *v,
-            None => panic!("Goto {} Missing. line {}", label, self.index + 1),
-        };
+    if let Err(e) = result {
+        eprintln!("{}", story.explain(&e));
+        std::process::exit(1);
     }
 
-    fn process_if(&mut self) {
-        let (count, left, mid, right) = self
-            .iftokenize(self.lines[self.index].clone(), ":")
-            .unwrap();
-        let exp = self.process_variables(&left[1..left.len()].to_string());
-        let mut cond = mid.trim();
-
-        if !self.process_expression(exp) {
-            match count {
-                3 => cond = right.trim(),
-                _ => {
-                    self.index += 1;
-                    return;
-                }
-            }
-        }
-
-        match &cond[0..1] {
-            "#" => self.process_goto(Some(cond.to_string())),
-            "@" => self.process_variable(Some(cond.to_string())),
-            "\"" => {
-                let s = cond[1..cond.len()].to_string();
+    finish(&story, &options);
+}
 
-                if !s.ends_with('"') {
-                    panic!("if you start with \" you must End with \" to be able to print. Error on line {}", self.index + 1);
-                }
+//answers sent by another program, through a named pipe or a unix socket it listens on.
+//once the other end goes away reads come back empty, the same as the end of stdin.
+fn input_source(path: &str) -> std::io::Result<Box<dyn BufRead>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::{fs::FileTypeExt, net::UnixStream};
 
-                self.printmove(&s.trim_end_matches('"').to_string())
-            }
-            _ => self.printmove(&cond.to_string()),
+        if std::fs::metadata(path)?.file_type().is_socket() {
+            return Ok(Box::new(BufReader::new(UnixStream::connect(path)?)));
         }
     }
-}
-
-fn clear() {
-    std::io::stdout().write_all(b"\x1b[2J\x1b[1;1H").unwrap()
-}
 
-fn read_line() -> String {
-    let mut rv = String::new();
-    io::stdin().read_line(&mut rv).unwrap();
-    rv.replace("\r\n", "").replace("\n", "")
+    Ok(Box::new(BufReader::new(File::open(path)?)))
 }
 
-fn parse_variables(line: &str) -> Vec<String> {
-    let arr: nom::IResult<&str, Vec<&str>> = many0(preceded(
-        take_until("@"),
-        preceded(tag("@"), is_not(" \0+-<>=().!#:;^/\\@[]")),
-    ))(line);
+//what is asked for once the story has ended.
+fn finish(story: &Renderer, options: &Options) {
+    if options.dump {
+        println!("{}", story.dump_variables());
+    }
 
-    match &arr {
-        Ok(v) => {
-            let mut ret = Vec::new();
+    if let Some(path) = &options.result {
+        let json = serde_json::to_string_pretty(&story.result()).unwrap();
 
-            for item in v.1.iter() {
-                ret.insert(0, (*item).to_string())
+        match &path[..] {
+            "-" => println!("{}", json),
+            _ => {
+                if let Err(why) = std::fs::write(path, json) {
+                    eprintln!("Could not write the result to {}, {}.", path, why);
+                    std::process::exit(1);
+                }
             }
-
-            ret
         }
-        _ => Vec::new(),
     }
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let path = Path::new(&args[1]);
-    let display = path.display();
-    let mut story = Renderer::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let file = match File::open(&path) {
-        Err(why) => panic!("couldn't open {}: {}", display, why),
-        Ok(file) => file,
-    };
+    fn args(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
 
-    story.processfile(file);
+    #[test]
+    fn a_story_file_is_needed_unless_asking_for_help() {
+        assert_eq!(
+            parse_args(&args("--lenient")).err(),
+            Some(String::from("no story file was given"))
+        );
 
-    while story.index < story.lines.len() {
-        let text = &story.lines[story.index];
+        let options = parse_args(&args("--help")).unwrap();
+        assert!(options.help);
+        assert!(options.path.is_empty());
 
-        if text.is_empty() {
-            story.index += 1;
-            continue;
-        }
+        let options = parse_args(&args("story.txt --seed 4")).unwrap();
+        assert_eq!(options.path, "story.txt");
+        assert_eq!(options.seed, Some(4));
+    }
 
-        match &text[0..1] {
-            "\n" | "\r" | ":" | "*" => story.index += 1,
-            "|" => {
-                println!();
-                story.index += 1;
-            }
-            "#" => story.process_goto(None),
-            "!" => story.process_if(),
-            "@" => story.process_variable(None),
-            "?" => story.process_questions(),
-            "^" => story.process_input(),
-            "~" => story.input_wait(),
-            "`" => story.clear_screen(),
-            _ => story.printmove(&story.lines[story.index].clone()),
-        }
+    #[test]
+    fn options_missing_their_value_are_errors() {
+        assert_eq!(
+            parse_args(&args("story.txt --advance-to")).err(),
+            Some(String::from("--advance-to requires a label name"))
+        );
+        assert_eq!(
+            parse_args(&args("story.txt --nope")).err(),
+            Some(String::from("--nope is not a known option"))
+        );
+    }
+
+    #[test]
+    fn checkpoints_are_only_saved_when_asked() {
+        let story = Path::new("tales/cave.txt");
+        let save = |text: &str| save_path(&parse_args(&args(text)).unwrap(), story);
+
+        assert_eq!(save("tales/cave.txt"), None);
+        assert_eq!(
+            save("tales/cave.txt --continue"),
+            Some(PathBuf::from("tales/cave.save"))
+        );
+        assert_eq!(
+            save("tales/cave.txt --save slot1.sav"),
+            Some(PathBuf::from("slot1.sav"))
+        );
+    }
+
+    #[test]
+    fn death_labels_need_the_whole_story() {
+        assert_eq!(
+            parse_args(&args("cave.txt --stream --death-label dead")).err(),
+            Some(String::from("--stream can not be used with --death-label"))
+        );
+        assert!(parse_args(&args("cave.txt --death-label dead")).is_ok());
     }
 }